@@ -14,23 +14,33 @@
 // You should have received a copy of the GNU General Public License
 // along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::custom_proto::{CustomProto, CustomProtoOut, RegisteredProtocol};
+use crate::custom_proto::{CustomProto, CustomProtoOut, ProtocolId, RegisteredProtocol};
 use futures::prelude::*;
 use libp2p::NetworkBehaviour;
 use libp2p::core::{Multiaddr, PeerId, ProtocolsHandler, PublicKey};
+use libp2p::core::multiaddr::Protocol;
 use libp2p::core::swarm::{ConnectedPoint, NetworkBehaviour, NetworkBehaviourAction};
 use libp2p::core::swarm::{NetworkBehaviourEventProcess, PollParameters};
 use libp2p::core::swarm::toggle::Toggle;
 use libp2p::identify::{Identify, IdentifyEvent, protocol::IdentifyInfo};
-use libp2p::kad::{Kademlia, KademliaOut};
+use libp2p::kad::{Kademlia, KademliaConfig, KademliaOut};
+use libp2p::kad::record::Key;
 use libp2p::mdns::{Mdns, MdnsEvent};
 use libp2p::ping::{Ping, PingEvent};
 use log::{debug, trace, warn};
-use std::{cmp, io, fmt, time::Duration, time::Instant};
+use std::{cmp, collections::HashMap, collections::HashSet, io, fmt, time::Duration, time::Instant};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_timer::Delay;
 use void;
 
+use crate::discovery_handler::MultiKademliaHandler;
+use crate::nat::{NatBehaviour, NatStatus, NatStatusChanged};
+use crate::relay::{RelayBehaviour, RelayEvent};
+
+/// Number of successful (or failed) dial-back probes required before flipping the NAT
+/// reachability determination.
+const NAT_QUORUM: usize = 3;
+
 /// General behaviour of the network.
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "BehaviourOut<TMessage>", poll_method = "poll")]
@@ -45,10 +55,56 @@ pub struct Behaviour<TMessage, TSubstream> {
 	identify: Identify<TSubstream>,
 	/// Discovers nodes on the local network.
 	mdns: Toggle<Mdns<TSubstream>>,
+	/// AutoNAT-style reachability probing, toggling Kademlia between server and client mode.
+	nat: Toggle<NatBehaviour<TSubstream>>,
+	/// Circuit relay dialing and DCUtR hole punching, for reaching peers that are both behind NATs.
+	relay: RelayBehaviour<TSubstream>,
 
 	/// Queue of events to produce for the outside.
 	#[behaviour(ignore)]
 	events: Vec<BehaviourOut<TMessage>>,
+
+	/// Decaying reputation score for each peer we've reported misbehaviour for.
+	#[behaviour(ignore)]
+	reputations: HashMap<PeerId, PeerReputation>,
+}
+
+/// Decaying reputation score of a single peer, used to decide when to ban it.
+struct PeerReputation {
+	/// Current score. The more negative, the worse the peer has behaved.
+	score: i32,
+	/// Last time the score was updated, used to apply the decay.
+	last_update: Instant,
+}
+
+/// Reputation change applied for a `Severity::Timeout` report.
+const REPUTATION_TIMEOUT: i32 = -1;
+/// Reputation change applied for a `Severity::Useless` report.
+const REPUTATION_USELESS: i32 = -10;
+/// Reputation change applied for a `Severity::Bad` report.
+const REPUTATION_BAD: i32 = -50;
+/// Once a peer's score drops to or below this value, it gets banned.
+const BAN_THRESHOLD: i32 = -100;
+/// How long a banned peer is refused reconnection for.
+const BAN_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+/// How much reputation a peer recovers per second, so that transient timeouts eventually heal.
+const REPUTATION_DECAY_PER_SECOND: i32 = 1;
+
+impl PeerReputation {
+	fn new(now: Instant) -> Self {
+		PeerReputation { score: 0, last_update: now }
+	}
+
+	/// Lets the score heal towards zero for the time elapsed since `last_update`, then applies
+	/// `delta`. Returns true if the peer should now be banned.
+	fn apply(&mut self, delta: i32, now: Instant) -> bool {
+		let healed = (now.duration_since(self.last_update).as_secs() as i32)
+			.saturating_mul(REPUTATION_DECAY_PER_SECOND);
+		self.score = cmp::min(0, self.score + healed);
+		self.last_update = now;
+		self.score = self.score.saturating_add(delta);
+		self.score <= BAN_THRESHOLD
+	}
 }
 
 impl<TMessage, TSubstream> Behaviour<TMessage, TSubstream> {
@@ -57,9 +113,9 @@ impl<TMessage, TSubstream> Behaviour<TMessage, TSubstream> {
 		user_agent: String,
 		local_public_key: PublicKey,
 		protocol: RegisteredProtocol<TMessage>,
-		known_addresses: Vec<(PeerId, Multiaddr)>,
 		peerset: peerset::PeersetMut,
-		enable_mdns: bool,
+		discovery_config: DiscoveryConfig,
+		enable_nat_probing: bool,
 	) -> Self {
 		let identify = {
 			let proto_version = format!("/{}/{}", crate::PROTOCOL_NAME, crate::PROTOCOL_VERSION).to_string();
@@ -67,21 +123,12 @@ impl<TMessage, TSubstream> Behaviour<TMessage, TSubstream> {
 		};
 
 		let custom_protocols = CustomProto::new(protocol, peerset);
-
-		let mut kademlia = Kademlia::new(local_public_key.into_peer_id());
-		for (peer_id, addr) in &known_addresses {
-			kademlia.add_connected_address(peer_id, addr.clone());
-		}
+		let enable_mdns = discovery_config.enable_mdns;
 
 		Behaviour {
 			ping: Ping::new(),
 			custom_protocols,
-			discovery: DiscoveryBehaviour {
-				user_defined: known_addresses,
-				kademlia,
-				next_kad_random_query: Delay::new(Instant::now()),
-				duration_to_next_kad: Duration::from_secs(1),
-			},
+			discovery: discovery_config.finish(),
 			identify,
 			mdns: if enable_mdns {
 				match Mdns::new() {
@@ -94,10 +141,37 @@ impl<TMessage, TSubstream> Behaviour<TMessage, TSubstream> {
 			} else {
 				None.into()
 			},
+			nat: if enable_nat_probing {
+				Some(NatBehaviour::new(NAT_QUORUM)).into()
+			} else {
+				None.into()
+			},
+			relay: RelayBehaviour::new(),
 			events: Vec::new(),
+			reputations: HashMap::new(),
 		}
 	}
 
+	/// Registers a candidate address that we believe might be externally reachable, so that the
+	/// NAT reachability probe (if enabled) can ask peers to dial us back on it.
+	pub fn add_nat_candidate_address(&mut self, addr: Multiaddr) {
+		if let Some(nat) = self.nat.as_mut() {
+			nat.add_candidate_address(addr);
+		}
+	}
+
+	/// Registers `peer_id` as a relay peer that can be used to reach other peers hiding behind a
+	/// NAT, and to attempt a DCUtR hole punch through.
+	pub fn add_relay(&mut self, peer_id: PeerId, addr: Multiaddr) {
+		self.relay.add_relay(peer_id, addr);
+	}
+
+	/// Registers a candidate address that we believe might be externally reachable, offered to
+	/// peers during a DCUtR hole punch address exchange.
+	pub fn add_relay_candidate_address(&mut self, addr: Multiaddr) {
+		self.relay.add_candidate_address(addr);
+	}
+
 	/// Sends a message to a peer.
 	///
 	/// Has no effect if the custom protocol is not open with the given peer.
@@ -111,7 +185,10 @@ impl<TMessage, TSubstream> Behaviour<TMessage, TSubstream> {
 
 	/// Returns the list of nodes that we know exist in the network.
 	pub fn known_peers(&self) -> impl Iterator<Item = &PeerId> {
-		self.discovery.kademlia.kbuckets_entries()
+		self.discovery.kademlias.values()
+			.flat_map(|k| k.kbuckets_entries())
+			.collect::<std::collections::HashSet<_>>()
+			.into_iter()
 	}
 
 	/// Returns true if we try to open protocols with the given peer.
@@ -149,6 +226,69 @@ impl<TMessage, TSubstream> Behaviour<TMessage, TSubstream> {
 	pub fn peerset_debug_info(&self) -> serde_json::Value {
 		self.custom_protocols.peerset_debug_info()
 	}
+
+	/// Reports misbehaviour by `peer_id` of the given `severity`, adjusting its reputation
+	/// accordingly. If the peer's reputation drops to or below the ban threshold, it is
+	/// disconnected and refused reconnection for a cooldown period.
+	pub fn report_peer(&mut self, peer_id: PeerId, severity: Severity) {
+		let delta = match &severity {
+			Severity::Timeout => REPUTATION_TIMEOUT,
+			Severity::Useless(_) => REPUTATION_USELESS,
+			Severity::Bad(_) => REPUTATION_BAD,
+		};
+
+		let now = Instant::now();
+		let reputation = self.reputations.entry(peer_id.clone())
+			.or_insert_with(|| PeerReputation::new(now));
+		let should_ban = reputation.apply(delta, now);
+
+		self.custom_protocols.report_peer(&peer_id, delta);
+
+		if should_ban {
+			let until = now + BAN_COOLDOWN;
+			warn!(target: crate::LOG_TARGET, "Banning {:?} until {:?}: {}", peer_id, until, severity);
+			// Ban through the peerset, which is what actually admits or refuses connections, so a
+			// banned peer can't just reconnect and bypass it; `discovery.ban_peer` on top of that
+			// only stops us from *offering* the peer's address, which a peer dialing us wouldn't
+			// even need.
+			self.custom_protocols.ban_peer(peer_id.clone(), until);
+			self.discovery.ban_peer(peer_id.clone(), until);
+			self.drop_node(&peer_id);
+			self.events.push(BehaviourOut::Banned { peer_id, severity, until });
+		}
+	}
+
+	/// Starts a Kademlia query for the value stored under `key`, across every registered
+	/// protocol's DHT. The result is reported through `BehaviourOut::Dht`.
+	pub fn get_value(&mut self, key: &Key) {
+		for kademlia in self.discovery.kademlias.values_mut() {
+			kademlia.get_value(key);
+		}
+	}
+
+	/// Stores `value` under `key` in every registered protocol's DHT. The result is reported
+	/// through `BehaviourOut::Dht`.
+	pub fn put_value(&mut self, key: Key, value: Vec<u8>) {
+		for kademlia in self.discovery.kademlias.values_mut() {
+			kademlia.put_value(key.clone(), value.clone());
+		}
+	}
+
+	/// Registers us as a provider for `key` in every registered protocol's DHT, so that peers
+	/// running `get_providers` for that key will find us.
+	pub fn start_providing(&mut self, key: Key) {
+		for kademlia in self.discovery.kademlias.values_mut() {
+			kademlia.start_providing(key.clone());
+		}
+	}
+
+	/// Starts a Kademlia query for the providers of `key`. The result is reported through
+	/// `BehaviourOut::Dht`.
+	pub fn get_providers(&mut self, key: Key) {
+		for kademlia in self.discovery.kademlias.values_mut() {
+			kademlia.get_providers(key.clone());
+		}
+	}
 }
 
 /// Event that can be emitted by the behaviour.
@@ -207,6 +347,73 @@ pub enum BehaviourOut<TMessage> {
 		/// Time it took for the ping to come back.
 		ping_time: Duration,
 	},
+
+	/// Our NAT reachability determination has changed.
+	NatStatus {
+		/// Whether we now believe ourselves to be publicly reachable.
+		public: bool,
+		/// The address that peers confirmed they could dial us back on, if any.
+		confirmed_address: Option<Multiaddr>,
+	},
+
+	/// Successfully established a direct connection to a NAT'd peer by hole punching.
+	HolePunched {
+		/// Id of the peer we punched through to.
+		peer_id: PeerId,
+		/// The direct connection's endpoint.
+		endpoint: ConnectedPoint,
+	},
+
+	/// A hole punch attempt failed; the service layer may want to retry or fall back to the
+	/// relayed connection.
+	HolePunchFailed {
+		/// Id of the peer the attempt was with.
+		peer_id: PeerId,
+	},
+
+	/// The result of a DHT put/get/providers query started with `Behaviour::put_value`,
+	/// `Behaviour::get_value`, `Behaviour::start_providing`, or `Behaviour::get_providers`.
+	Dht(DhtEvent),
+
+	/// A peer's reputation dropped to or below the ban threshold; it has been disconnected and
+	/// will be refused reconnection until `until`.
+	Banned {
+		/// Id of the peer that got banned.
+		peer_id: PeerId,
+		/// Severity of the report that caused the ban.
+		severity: Severity,
+		/// The peer will be refused reconnection until this instant.
+		until: Instant,
+	},
+}
+
+/// The result of a DHT query, reported via `BehaviourOut::Dht`.
+///
+/// Carries the `protocol` whose DHT the result came from, since more than one may be registered
+/// (see `DiscoveryConfig::add_protocol`); without it, a single `get_value` call could produce
+/// several `ValueFound`/`ValueNotFound` events for the same key with no way to tell which chain's
+/// DHT actually answered.
+#[derive(Debug, Clone)]
+pub struct DhtEvent {
+	/// The protocol whose Kademlia instance produced this result.
+	pub protocol: ProtocolId,
+	/// The result itself.
+	pub kind: DhtEventKind,
+}
+
+/// The kind of DHT query result carried by a `DhtEvent`.
+#[derive(Debug, Clone)]
+pub enum DhtEventKind {
+	/// Found a value for a key we queried.
+	ValueFound(Vec<(Key, Vec<u8>)>),
+	/// Could not find a value for a key we queried.
+	ValueNotFound(Key),
+	/// Successfully put a value under a key.
+	ValuePut(Key),
+	/// Failed to put a value under a key.
+	ValuePutFailed(Key),
+	/// Found the providers for a key we queried.
+	ProvidersFound(Key, Vec<PeerId>),
 }
 
 impl<TMessage> From<CustomProtoOut<TMessage>> for BehaviourOut<TMessage> {
@@ -255,24 +462,44 @@ impl<TMessage, TSubstream> NetworkBehaviourEventProcess<IdentifyEvent> for Behav
 					warn!(target: crate::LOG_TARGET, "Node {:?} id reported more than 30 addresses",
 						peer_id);
 					info.listen_addrs.truncate(30);
+					self.report_peer(peer_id.clone(), Severity::Useless(
+						"reported more than 30 addresses".to_string()
+					));
 				}
+				let supported: Vec<ProtocolId> = self.discovery.kademlias.keys()
+					.filter(|protocol| info.protocols.iter().any(|p| p.as_bytes() == kad_protocol_name(protocol).as_slice()))
+					.cloned()
+					.collect();
+				self.discovery.note_supported_protocols(&peer_id, &supported);
+
 				for addr in &info.listen_addrs {
-					self.discovery.kademlia.add_connected_address(&peer_id, addr.clone());
+					if !self.discovery.allow_private_ipv4 && is_local_or_private(addr) {
+						continue;
+					}
+					for protocol in &supported {
+						if let Some(kademlia) = self.discovery.kademlias.get_mut(protocol) {
+							kademlia.add_connected_address(&peer_id, addr.clone());
+						}
+					}
 				}
 				self.custom_protocols.add_discovered_node(&peer_id);
 				self.events.push(BehaviourOut::Identified { peer_id, info });
 			}
 			IdentifyEvent::Error { .. } => {}
-			IdentifyEvent::SendBack { result: Err(ref err), ref peer_id } =>
+			IdentifyEvent::SendBack { result: Err(ref err), ref peer_id } => {
 				debug!(target: crate::LOG_TARGET, "Error when sending back identify info \
-					to {:?} => {}", peer_id, err),
+					to {:?} => {}", peer_id, err);
+				self.report_peer(peer_id.clone(), Severity::Timeout);
+			}
 			IdentifyEvent::SendBack { .. } => {}
 		}
 	}
 }
 
-impl<TMessage, TSubstream> NetworkBehaviourEventProcess<KademliaOut> for Behaviour<TMessage, TSubstream> {
-	fn inject_event(&mut self, out: KademliaOut) {
+impl<TMessage, TSubstream> NetworkBehaviourEventProcess<(ProtocolId, KademliaOut)> for Behaviour<TMessage, TSubstream> {
+	fn inject_event(&mut self, (protocol, out): (ProtocolId, KademliaOut)) {
+		let dht_event = |kind| BehaviourOut::Dht(DhtEvent { protocol: protocol.clone(), kind });
+
 		match out {
 			KademliaOut::Discovered { .. } => {}
 			KademliaOut::KBucketAdded { peer_id, .. } => {
@@ -286,14 +513,34 @@ impl<TMessage, TSubstream> NetworkBehaviourEventProcess<KademliaOut> for Behavio
 						results");
 				}
 			}
-			// We never start any GET_PROVIDERS query.
-			KademliaOut::GetProvidersResult { .. } => ()
+			KademliaOut::GetRecordResult(Ok(result)) => {
+				let records = result.records.into_iter().map(|r| (r.key, r.value)).collect();
+				self.events.push(dht_event(DhtEventKind::ValueFound(records)));
+			}
+			KademliaOut::GetRecordResult(Err(err)) => {
+				trace!(target: crate::LOG_TARGET, "Libp2p => Failed to get record: {:?}", err);
+				self.events.push(dht_event(DhtEventKind::ValueNotFound(err.key())));
+			}
+			KademliaOut::PutRecordResult(Ok(result)) => {
+				self.events.push(dht_event(DhtEventKind::ValuePut(result.key)));
+			}
+			KademliaOut::PutRecordResult(Err(err)) => {
+				trace!(target: crate::LOG_TARGET, "Libp2p => Failed to put record: {:?}", err);
+				self.events.push(dht_event(DhtEventKind::ValuePutFailed(err.key())));
+			}
+			KademliaOut::GetProvidersResult(result) => {
+				self.events.push(dht_event(DhtEventKind::ProvidersFound(result.key, result.provider_peers)));
+			}
 		}
 	}
 }
 
 impl<TMessage, TSubstream> NetworkBehaviourEventProcess<PingEvent> for Behaviour<TMessage, TSubstream> {
 	fn inject_event(&mut self, event: PingEvent) {
+		// `ping` doesn't report failures as an event; an unresponsive peer just has its
+		// connection closed by the protocol handler, so there's no `Severity::Timeout` to
+		// auto-report here. Identify is the only handler that currently detects misbehaviour on
+		// its own (see `IdentifyEvent::Identified` and `IdentifyEvent::SendBack` above).
 		match event {
 			PingEvent::PingSuccess { peer, time } => {
 				trace!(target: crate::LOG_TARGET, "Ping time with {:?}: {:?}", peer, time);
@@ -303,6 +550,43 @@ impl<TMessage, TSubstream> NetworkBehaviourEventProcess<PingEvent> for Behaviour
 	}
 }
 
+impl<TMessage, TSubstream> NetworkBehaviourEventProcess<NatStatusChanged> for Behaviour<TMessage, TSubstream> {
+	fn inject_event(&mut self, event: NatStatusChanged) {
+		let (public, confirmed_address) = match event.status {
+			NatStatus::Public(addr) => (true, Some(addr)),
+			NatStatus::Private => (false, None),
+			NatStatus::Unknown => return,
+		};
+
+		// Behind a NAT we stop advertising ourselves and stop storing records on behalf of
+		// others, but we keep issuing queries so we can still resolve the DHT.
+		for kademlia in self.discovery.kademlias.values_mut() {
+			kademlia.set_server_mode(public);
+		}
+
+		if let Some(ref addr) = confirmed_address {
+			// Identify reports our listened/external addresses to peers; once confirmed, this
+			// address takes part in that advertisement.
+			self.identify.add_external_addr(addr.clone());
+		}
+
+		self.events.push(BehaviourOut::NatStatus { public, confirmed_address });
+	}
+}
+
+impl<TMessage, TSubstream> NetworkBehaviourEventProcess<RelayEvent> for Behaviour<TMessage, TSubstream> {
+	fn inject_event(&mut self, event: RelayEvent) {
+		match event {
+			RelayEvent::HolePunched { peer_id, endpoint } => {
+				self.events.push(BehaviourOut::HolePunched { peer_id, endpoint });
+			}
+			RelayEvent::HolePunchFailed { peer_id } => {
+				self.events.push(BehaviourOut::HolePunchFailed { peer_id });
+			}
+		}
+	}
+}
+
 impl<TMessage, TSubstream> NetworkBehaviourEventProcess<MdnsEvent> for Behaviour<TMessage, TSubstream> {
 	fn inject_event(&mut self, event: MdnsEvent) {
 		match event {
@@ -326,38 +610,254 @@ impl<TMessage, TSubstream> Behaviour<TMessage, TSubstream> {
 	}
 }
 
+/// Builder for a `DiscoveryBehaviour`.
+pub struct DiscoveryConfig {
+	local_peer_id: PeerId,
+	user_defined: Vec<(PeerId, Multiaddr)>,
+	protocols: Vec<ProtocolId>,
+	enable_mdns: bool,
+	discovery_only_if_under_num: u64,
+	allow_private_ipv4: bool,
+}
+
+impl DiscoveryConfig {
+	/// Creates a new configuration for a `DiscoveryBehaviour`, for the given peer id.
+	pub fn new(local_peer_id: PeerId) -> Self {
+		DiscoveryConfig {
+			local_peer_id,
+			user_defined: Vec::new(),
+			protocols: Vec::new(),
+			enable_mdns: false,
+			discovery_only_if_under_num: std::u64::MAX,
+			allow_private_ipv4: true,
+		}
+	}
+
+	/// Adds a hard-coded address for a node to the discovery mechanism.
+	pub fn add_bootnode(&mut self, peer_id: PeerId, addr: Multiaddr) -> &mut Self {
+		self.user_defined.push((peer_id, addr));
+		self
+	}
+
+	/// Adds a protocol to be discovered. A separate Kademlia DHT is kept for each protocol, so
+	/// that nodes that only share a subset of protocols don't pollute each other's routing
+	/// tables.
+	pub fn add_protocol(&mut self, id: ProtocolId) -> &mut Self {
+		if !self.protocols.contains(&id) {
+			self.protocols.push(id);
+		}
+		self
+	}
+
+	/// Sets whether nodes on the local network should be discovered through mDNS.
+	pub fn with_mdns(&mut self, value: bool) -> &mut Self {
+		self.enable_mdns = value;
+		self
+	}
+
+	/// Sets the number of connected peers below which we keep issuing random Kademlia queries
+	/// to discover more nodes. Once we have at least that many peers, the random walk is
+	/// suspended to save bandwidth.
+	pub fn discovery_limit(&mut self, limit: u64) -> &mut Self {
+		self.discovery_only_if_under_num = limit;
+		self
+	}
+
+	/// Sets whether the discovery mechanism is allowed to use private IPv4 addresses (RFC1918)
+	/// and loopback addresses. Should be `false` for a node that is reachable from the public
+	/// internet, so that it doesn't advertise or dial unreachable LAN addresses.
+	pub fn allow_private_ipv4(&mut self, value: bool) -> &mut Self {
+		self.allow_private_ipv4 = value;
+		self
+	}
+
+	/// Creates the `DiscoveryBehaviour` described by this configuration.
+	pub fn finish<TSubstream>(self) -> DiscoveryBehaviour<TSubstream> {
+		let kademlias = self.protocols.into_iter()
+			.map(|protocol| {
+				// Each instance must negotiate a distinct wire protocol name, both so a peer that
+				// only supports a subset of our registered protocols can be told apart by Identify
+				// (see `kad_protocol_name`'s other caller) and so `MultiKademliaHandler` actually
+				// demultiplexes substreams instead of every instance answering to the same name.
+				let mut config = KademliaConfig::default();
+				config.set_protocol_name(kad_protocol_name(&protocol));
+				let mut kademlia = Kademlia::with_config(self.local_peer_id.clone(), config);
+				for (peer_id, addr) in &self.user_defined {
+					kademlia.add_connected_address(peer_id, addr.clone());
+				}
+				(protocol, kademlia)
+			})
+			.collect();
+
+		DiscoveryBehaviour {
+			user_defined: self.user_defined,
+			kademlias,
+			next_kad_random_query: Delay::new(Instant::now()),
+			duration_to_next_kad: Duration::from_secs(1),
+			discovery_only_if_under_num: self.discovery_only_if_under_num,
+			allow_private_ipv4: self.allow_private_ipv4,
+			banned: HashMap::new(),
+			connected: HashMap::new(),
+			peer_protocols: HashMap::new(),
+		}
+	}
+}
+
 /// Implementation of `NetworkBehaviour` that discovers the nodes on the network.
 pub struct DiscoveryBehaviour<TSubstream> {
 	/// User-defined list of nodes and their addresses. Typically includes bootstrap nodes and
 	/// reserved nodes.
 	user_defined: Vec<(PeerId, Multiaddr)>,
-	/// Kademlia requests and answers.
-	kademlia: Kademlia<TSubstream>,
+	/// Kademlia requests and answers, one per registered protocol. Each protocol gets its own
+	/// DHT routing table so that running several chains on one node doesn't mix their peers.
+	kademlias: HashMap<ProtocolId, Kademlia<TSubstream>>,
 	/// Stream that fires when we need to perform the next random Kademlia query.
 	next_kad_random_query: Delay,
 	/// After `next_kad_random_query` triggers, the next one triggers after this duration.
 	duration_to_next_kad: Duration,
+	/// Don't issue new random Kademlia queries if we already have at least this many peers.
+	discovery_only_if_under_num: u64,
+	/// If false, `addresses_of_peer` and the addresses fed into the k-buckets never contain
+	/// private (RFC1918) or loopback addresses.
+	allow_private_ipv4: bool,
+	/// Peers banned through `Behaviour::report_peer`, and until when they stay banned.
+	banned: HashMap<PeerId, Instant>,
+	/// Currently connected peers, and the endpoint we're connected to them on. Used to register
+	/// a peer with a specific protocol's Kademlia once we learn (through Identify) that it
+	/// actually supports that protocol.
+	connected: HashMap<PeerId, ConnectedPoint>,
+	/// For each connected peer, the set of registered protocols it's confirmed to support.
+	/// Only these Kademlia instances are told about the connection, so that a peer which only
+	/// speaks one chain's protocol doesn't end up in every other chain's k-buckets.
+	peer_protocols: HashMap<PeerId, HashSet<ProtocolId>>,
+}
+
+/// Returns the wire protocol name used for the Kademlia instance registered under `protocol`.
+///
+/// This is the single source of truth for that instance's name: it's fed into the instance's
+/// own `KademliaConfig` in `DiscoveryConfig::finish`, so it's also what peers actually negotiate
+/// on the wire and what Identify reports back in `info.protocols`, which is what lets us compare
+/// the two in the `IdentifyEvent::Identified` handler.
+fn kad_protocol_name(protocol: &ProtocolId) -> Vec<u8> {
+	let mut name = b"/substrate/kad/".to_vec();
+	name.extend_from_slice(protocol.as_ref());
+	name.extend_from_slice(b"/1.0.0");
+	name
+}
+
+impl<TSubstream> DiscoveryBehaviour<TSubstream> {
+	/// Refuses dialing `peer_id` until `until`.
+	fn ban_peer(&mut self, peer_id: PeerId, until: Instant) {
+		self.banned.insert(peer_id, until);
+	}
+
+	/// Returns true if `peer_id` is currently within its ban cooldown.
+	fn is_banned(&self, peer_id: &PeerId) -> bool {
+		self.banned.get(peer_id).map_or(false, |until| Instant::now() < *until)
+	}
+
+	/// Call once we learn (typically via Identify's reported `protocols`) which of our
+	/// registered protocols `peer_id` actually supports. Registers the peer with the
+	/// corresponding Kademlia instances if it's currently connected and wasn't already
+	/// registered with them.
+	pub fn note_supported_protocols(&mut self, peer_id: &PeerId, protocols: &[ProtocolId]) {
+		if self.is_banned(peer_id) {
+			return;
+		}
+
+		let endpoint = match self.connected.get(peer_id) {
+			Some(endpoint) => endpoint.clone(),
+			None => return,
+		};
+
+		let known = self.peer_protocols.entry(peer_id.clone()).or_insert_with(HashSet::new);
+		for protocol in protocols {
+			if !known.insert(protocol.clone()) {
+				continue;
+			}
+			if let Some(kademlia) = self.kademlias.get_mut(protocol) {
+				NetworkBehaviour::inject_connected(kademlia, peer_id.clone(), endpoint.clone());
+			}
+		}
+	}
+}
+
+/// Rewrites a `NetworkBehaviourAction` produced by a single protocol's `Kademlia` so that its
+/// `SendEvent` case carries the `ProtocolId` it belongs to (matching
+/// `MultiKademliaHandler::InEvent`) and its `GenerateEvent` case carries it too (so a caller
+/// running several chains can tell which one a `DhtEvent`-bound result actually came from).
+/// Every other case passes through unchanged.
+fn tag_action<TInEvent, TOutEvent>(
+	protocol: ProtocolId,
+	action: NetworkBehaviourAction<TInEvent, TOutEvent>,
+) -> NetworkBehaviourAction<(ProtocolId, TInEvent), (ProtocolId, TOutEvent)> {
+	match action {
+		NetworkBehaviourAction::GenerateEvent(event) =>
+			NetworkBehaviourAction::GenerateEvent((protocol, event)),
+		NetworkBehaviourAction::DialAddress { address } => NetworkBehaviourAction::DialAddress { address },
+		NetworkBehaviourAction::DialPeer { peer_id } => NetworkBehaviourAction::DialPeer { peer_id },
+		NetworkBehaviourAction::SendEvent { peer_id, event } =>
+			NetworkBehaviourAction::SendEvent { peer_id, event: (protocol, event) },
+		NetworkBehaviourAction::ReportObservedAddr { address } =>
+			NetworkBehaviourAction::ReportObservedAddr { address },
+	}
+}
+
+/// Returns true if `ip` falls in the unique-local (`fc00::/7`) range, IPv6's equivalent of
+/// RFC1918: reachable only within a site, never from the public internet.
+fn is_ipv6_unique_local(ip: &std::net::Ipv6Addr) -> bool {
+	(ip.segments()[0] & 0xfe00) == 0xfc00
+}
+
+/// Returns true if `ip` falls in the link-local (`fe80::/10`) range.
+fn is_ipv6_link_local(ip: &std::net::Ipv6Addr) -> bool {
+	(ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// Returns true if the address refers to a private (RFC1918), loopback, or link-local IP — v4 or
+/// v6 — i.e. one that's never reachable from the public internet and so shouldn't be announced
+/// to the DHT.
+fn is_local_or_private(addr: &Multiaddr) -> bool {
+	match addr.iter().next() {
+		Some(Protocol::Ip4(ip)) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+		Some(Protocol::Ip6(ip)) =>
+			ip.is_loopback() || is_ipv6_unique_local(&ip) || is_ipv6_link_local(&ip),
+		_ => false,
+	}
 }
 
 impl<TSubstream> NetworkBehaviour for DiscoveryBehaviour<TSubstream>
 where
 	TSubstream: AsyncRead + AsyncWrite,
 {
-	type ProtocolsHandler = <Kademlia<TSubstream> as NetworkBehaviour>::ProtocolsHandler;
-	type OutEvent = <Kademlia<TSubstream> as NetworkBehaviour>::OutEvent;
+	type ProtocolsHandler = MultiKademliaHandler<TSubstream>;
+	type OutEvent = (ProtocolId, <Kademlia<TSubstream> as NetworkBehaviour>::OutEvent);
 
 	fn new_handler(&mut self) -> Self::ProtocolsHandler {
-		NetworkBehaviour::new_handler(&mut self.kademlia)
+		// One inner handler per registered protocol, multiplexed by wire protocol name so that
+		// a substream only ever reaches the Kademlia instance it was actually negotiated for.
+		// If no protocol is registered yet, this is simply an empty handler that never
+		// negotiates anything, rather than panicking.
+		MultiKademliaHandler::new(&mut self.kademlias)
 	}
 
 	fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+		if self.is_banned(peer_id) {
+			return Vec::new();
+		}
+
 		let mut list = self.user_defined.iter()
 			.filter_map(|(p, a)| if p == peer_id { Some(a.clone()) } else { None })
 			.collect::<Vec<_>>();
-		list.extend(self.kademlia.addresses_of_peer(peer_id));
+		for kademlia in self.kademlias.values_mut() {
+			list.extend(kademlia.addresses_of_peer(peer_id));
+		}
+		if !self.allow_private_ipv4 {
+			list.retain(|addr| !is_local_or_private(addr));
+		}
 		trace!(target: crate::LOG_TARGET, "Addresses of {:?} are {:?}", peer_id, list);
 		if list.is_empty() {
-			if self.kademlia.kbuckets_entries().any(|p| p == peer_id) {
+			if self.kademlias.values().any(|k| k.kbuckets_entries().any(|p| p == peer_id)) {
 				debug!(target: crate::LOG_TARGET, "Requested dialing to {:?} (peer in k-buckets), \
 					and no address was found", peer_id);
 			} else {
@@ -369,23 +869,59 @@ where
 	}
 
 	fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
-		NetworkBehaviour::inject_connected(&mut self.kademlia, peer_id, endpoint)
+		// We don't yet know which, if any, of our registered protocols this peer supports; that
+		// is only known once Identify reports back and calls `note_supported_protocols`. Record
+		// the connection so that call can look the endpoint back up, and register the peer with
+		// any protocols already recorded for it in `peer_protocols`. `inject_disconnected` clears
+		// that record, so after a reconnection it is empty until Identify reports back again.
+		self.connected.insert(peer_id.clone(), endpoint.clone());
+		// Connection admission itself is refused through the peerset (see
+		// `Behaviour::report_peer`); this is just a defensive second line so a banned peer that
+		// somehow still gets a connection through doesn't end up back in our k-buckets.
+		if self.is_banned(&peer_id) {
+			return;
+		}
+		if let Some(protocols) = self.peer_protocols.get(&peer_id).cloned() {
+			for protocol in protocols {
+				if let Some(kademlia) = self.kademlias.get_mut(&protocol) {
+					NetworkBehaviour::inject_connected(kademlia, peer_id.clone(), endpoint.clone());
+				}
+			}
+		}
 	}
 
 	fn inject_disconnected(&mut self, peer_id: &PeerId, endpoint: ConnectedPoint) {
-		NetworkBehaviour::inject_disconnected(&mut self.kademlia, peer_id, endpoint)
+		self.connected.remove(peer_id);
+		if let Some(protocols) = self.peer_protocols.remove(peer_id) {
+			for protocol in protocols {
+				if let Some(kademlia) = self.kademlias.get_mut(&protocol) {
+					NetworkBehaviour::inject_disconnected(kademlia, peer_id, endpoint.clone());
+				}
+			}
+		}
 	}
 
 	fn inject_replaced(&mut self, peer_id: PeerId, closed: ConnectedPoint, opened: ConnectedPoint) {
-		NetworkBehaviour::inject_replaced(&mut self.kademlia, peer_id, closed, opened)
+		self.connected.insert(peer_id.clone(), opened.clone());
+		if let Some(protocols) = self.peer_protocols.get(&peer_id).cloned() {
+			for protocol in protocols {
+				if let Some(kademlia) = self.kademlias.get_mut(&protocol) {
+					NetworkBehaviour::inject_replaced(kademlia, peer_id.clone(), closed.clone(), opened.clone());
+				}
+			}
+		}
 	}
 
 	fn inject_node_event(
 		&mut self,
 		peer_id: PeerId,
-		event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
+		(protocol, event): <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
 	) {
-		NetworkBehaviour::inject_node_event(&mut self.kademlia, peer_id, event)
+		// The handler already demultiplexed by wire protocol name, so this event belongs to
+		// exactly one Kademlia instance; no more fanning it out to every other chain's DHT.
+		if let Some(kademlia) = self.kademlias.get_mut(&protocol) {
+			NetworkBehaviour::inject_node_event(kademlia, peer_id, event);
+		}
 	}
 
 	fn poll(
@@ -397,21 +933,33 @@ where
 			Self::OutEvent,
 		>,
 	> {
-		// Poll Kademlia.
-		match self.kademlia.poll(params) {
-			Async::Ready(action) => return Async::Ready(action),
-			Async::NotReady => (),
+		// Poll each Kademlia instance in turn, tagging any `SendEvent` action with the protocol
+		// it came from so `MultiKademliaHandler` can route it back to the right inner handler.
+		for (protocol, kademlia) in self.kademlias.iter_mut() {
+			match kademlia.poll(params) {
+				Async::Ready(action) => return Async::Ready(tag_action(protocol.clone(), action)),
+				Async::NotReady => (),
+			}
 		}
 
-		// Poll the stream that fires when we need to start a random Kademlia query.
+		// Poll the stream that fires when we need to start a random Kademlia query on every
+		// registered protocol.
 		loop {
 			match self.next_kad_random_query.poll() {
 				Ok(Async::NotReady) => break,
 				Ok(Async::Ready(_)) => {
-					let random_peer_id = PeerId::random();
-					debug!(target: crate::LOG_TARGET, "Libp2p <= Starting random Kademlia request for \
-						{:?}", random_peer_id);
-					self.kademlia.find_node(random_peer_id);
+					let num_connected = params.connected_peers() as u64;
+					if num_connected < self.discovery_only_if_under_num {
+						let random_peer_id = PeerId::random();
+						debug!(target: crate::LOG_TARGET, "Libp2p <= Starting random Kademlia \
+							request for {:?}", random_peer_id);
+						for kademlia in self.kademlias.values_mut() {
+							kademlia.find_node(random_peer_id.clone());
+						}
+					} else {
+						trace!(target: crate::LOG_TARGET, "Libp2p <= Skipping random Kademlia \
+							query ({} peers already connected)", num_connected);
+					}
 
 					// Reset the `Delay` to the next random.
 					self.next_kad_random_query.reset(Instant::now() + self.duration_to_next_kad);
@@ -451,3 +999,67 @@ impl fmt::Display for Severity {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reputation_decays_towards_zero_before_penalty() {
+		let t0 = Instant::now();
+		let mut reputation = PeerReputation::new(t0);
+		assert!(!reputation.apply(REPUTATION_USELESS, t0));
+		assert_eq!(reputation.score, REPUTATION_USELESS);
+
+		// Five seconds of healing, then no new penalty.
+		let t1 = t0 + Duration::from_secs(5);
+		assert!(!reputation.apply(0, t1));
+		assert_eq!(reputation.score, REPUTATION_USELESS + 5);
+	}
+
+	#[test]
+	fn reputation_decay_never_goes_positive() {
+		let t0 = Instant::now();
+		let mut reputation = PeerReputation::new(t0);
+		reputation.apply(REPUTATION_TIMEOUT, t0);
+
+		let much_later = t0 + Duration::from_secs(1_000);
+		reputation.apply(0, much_later);
+		assert_eq!(reputation.score, 0);
+	}
+
+	#[test]
+	fn reputation_bans_once_threshold_crossed() {
+		// REPUTATION_BAD (-50) twice already reaches BAN_THRESHOLD (-100), so the ban triggers on
+		// the 2nd report, not the 3rd.
+		let t0 = Instant::now();
+		let mut reputation = PeerReputation::new(t0);
+		assert!(!reputation.apply(REPUTATION_BAD, t0));
+		assert!(reputation.apply(REPUTATION_BAD, t0));
+		assert!(reputation.score <= BAN_THRESHOLD);
+	}
+
+	#[test]
+	fn ipv4_loopback_and_private_ranges_are_filtered() {
+		let loopback: Multiaddr = "/ip4/127.0.0.1/tcp/30333".parse().unwrap();
+		let rfc1918: Multiaddr = "/ip4/192.168.1.5/tcp/30333".parse().unwrap();
+		let link_local: Multiaddr = "/ip4/169.254.1.1/tcp/30333".parse().unwrap();
+		assert!(is_local_or_private(&loopback));
+		assert!(is_local_or_private(&rfc1918));
+		assert!(is_local_or_private(&link_local));
+	}
+
+	#[test]
+	fn ipv6_loopback_is_filtered() {
+		let loopback: Multiaddr = "/ip6/::1/tcp/30333".parse().unwrap();
+		assert!(is_local_or_private(&loopback));
+	}
+
+	#[test]
+	fn public_addresses_are_not_filtered() {
+		let v4: Multiaddr = "/ip4/8.8.8.8/tcp/30333".parse().unwrap();
+		let v6: Multiaddr = "/ip6/2001:4860:4860::8888/tcp/30333".parse().unwrap();
+		assert!(!is_local_or_private(&v4));
+		assert!(!is_local_or_private(&v6));
+	}
+}
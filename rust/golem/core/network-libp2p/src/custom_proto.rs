@@ -0,0 +1,367 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The "custom" (application-level) protocol that rides on top of every connection the peerset
+//! admits.
+//!
+//! `CustomProto` doesn't run its own handshake or keep a notion of "open" independent from the
+//! connection: whether we dial or accept `peer_id` at all is entirely the peerset's call, driven
+//! through [`CustomProto::report_peer`] and [`CustomProto::ban_peer`]. Once a connection exists
+//! and the peer isn't locally banned, the protocol is considered open for as long as the
+//! connection lasts; outgoing messages are then queued and each sent as a one-shot framed
+//! payload on its own substream, the same pattern [`crate::relay`] and [`crate::nat`] use for
+//! their own short-lived protocol exchanges.
+
+use futures::prelude::*;
+use futures::future;
+use libp2p::core::{ConnectedPoint, InboundUpgrade, Multiaddr, OutboundUpgrade, PeerId, ProtocolsHandler, UpgradeInfo};
+use libp2p::core::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::core::protocols_handler::{OneShotHandler, OneShotHandlerConfig};
+use std::{borrow::Cow, collections::HashMap, io, time::Instant};
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Upper bound on a single framed message, so a peer can't make us allocate an unbounded buffer
+/// by sending a bogus length prefix.
+const MAX_MESSAGE_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Identifies one of the custom protocols registered with a [`CustomProto`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProtocolId(Cow<'static, [u8]>);
+
+impl From<&'static [u8]> for ProtocolId {
+	fn from(name: &'static [u8]) -> Self {
+		ProtocolId(Cow::Borrowed(name))
+	}
+}
+
+impl AsRef<[u8]> for ProtocolId {
+	fn as_ref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+/// A custom protocol registered with a [`CustomProto`], carrying messages of type `TMessage`.
+///
+/// Also doubles as the inbound listening upgrade: an incoming substream only needs to know the
+/// wire protocol name to negotiate, not a message to send.
+pub struct RegisteredProtocol<TMessage> {
+	id: ProtocolId,
+	marker: std::marker::PhantomData<TMessage>,
+}
+
+impl<TMessage> RegisteredProtocol<TMessage> {
+	/// Creates a new `RegisteredProtocol` with the given wire protocol name.
+	pub fn new(id: impl Into<ProtocolId>) -> Self {
+		RegisteredProtocol { id: id.into(), marker: std::marker::PhantomData }
+	}
+}
+
+// Implemented manually (rather than `#[derive(Clone)]`) so that cloning doesn't spuriously
+// require `TMessage: Clone`: the field carrying it is only ever a zero-sized `PhantomData`.
+impl<TMessage> Clone for RegisteredProtocol<TMessage> {
+	fn clone(&self) -> Self {
+		RegisteredProtocol { id: self.id.clone(), marker: std::marker::PhantomData }
+	}
+}
+
+impl<TMessage> UpgradeInfo for RegisteredProtocol<TMessage> {
+	type Info = ProtocolId;
+	type InfoIter = std::iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		std::iter::once(self.id.clone())
+	}
+}
+
+impl<TSubstream, TMessage> InboundUpgrade<TSubstream> for RegisteredProtocol<TMessage>
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+	TMessage: From<Vec<u8>> + Send + 'static,
+{
+	type Output = Option<TMessage>;
+	type Error = io::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error> + Send>;
+
+	fn upgrade_inbound(self, socket: TSubstream, _info: Self::Info) -> Self::Future {
+		let message_future = read_exact(socket, [0u8; 4])
+			.and_then(|(socket, len_buf)| {
+				let len = u32::from_le_bytes(len_buf);
+				if len > MAX_MESSAGE_SIZE {
+					let err = io::Error::new(io::ErrorKind::InvalidData, "custom protocol message exceeds max size");
+					return future::Either::A(future::err::<(TSubstream, Vec<u8>), io::Error>(err));
+				}
+				future::Either::B(read_exact(socket, vec![0u8; len as usize]))
+			})
+			.map(|(_socket, buf)| Some(TMessage::from(buf)));
+		Box::new(message_future)
+	}
+}
+
+/// Outbound upgrade carrying a single queued message of a [`CustomProto`]'s registered protocol.
+pub struct OutboundMessage<TMessage> {
+	protocol: ProtocolId,
+	message: TMessage,
+}
+
+impl<TMessage> UpgradeInfo for OutboundMessage<TMessage> {
+	type Info = ProtocolId;
+	type InfoIter = std::iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		std::iter::once(self.protocol.clone())
+	}
+}
+
+impl<TSubstream, TMessage> OutboundUpgrade<TSubstream> for OutboundMessage<TMessage>
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+	TMessage: Into<Vec<u8>> + Send + 'static,
+{
+	type Output = Option<TMessage>;
+	type Error = io::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error> + Send>;
+
+	fn upgrade_outbound(self, socket: TSubstream, _info: Self::Info) -> Self::Future {
+		let payload: Vec<u8> = self.message.into();
+		let mut framed = Vec::with_capacity(4 + payload.len());
+		framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+		framed.extend_from_slice(&payload);
+		Box::new(write_all(socket, framed).map(|_| None))
+	}
+}
+
+/// Event emitted by [`CustomProto`].
+#[derive(Debug)]
+pub enum CustomProtoOut<TMessage> {
+	/// Opened the custom protocol with the remote.
+	CustomProtocolOpen {
+		/// Version of the protocol that has been opened.
+		version: u8,
+		/// Id of the node we have opened a connection with.
+		peer_id: PeerId,
+		/// Endpoint used for this custom protocol.
+		endpoint: ConnectedPoint,
+	},
+	/// Closed the custom protocol with the remote.
+	CustomProtocolClosed {
+		/// Id of the peer we were connected to.
+		peer_id: PeerId,
+		/// Endpoint used for this custom protocol.
+		endpoint: ConnectedPoint,
+		/// Reason why the substream closed. If `Ok`, then it's a graceful exit (EOF).
+		result: io::Result<()>,
+	},
+	/// Received a message on a custom protocol substream.
+	CustomMessage {
+		/// Id of the peer the message came from.
+		peer_id: PeerId,
+		/// Endpoint used for this custom protocol.
+		endpoint: ConnectedPoint,
+		/// Message that has been received.
+		message: TMessage,
+	},
+	/// A substream with a remote is clogged. We should avoid sending more data to it if possible.
+	Clogged {
+		/// Id of the peer the message came from.
+		peer_id: PeerId,
+		/// Copy of the messages that are within the buffer, for further diagnostic.
+		messages: Vec<TMessage>,
+	},
+}
+
+/// `NetworkBehaviour` running the custom protocol on top of every connection the peerset admits.
+///
+/// Connection admission itself — whether we dial or accept `peer_id` at all — is entirely the
+/// peerset's call; see [`CustomProto::report_peer`] and [`CustomProto::ban_peer`], which are the
+/// only things that actually influence it. `banned` is only a local, immediate-effect mirror of
+/// that decision so an already-connected banned peer gets dropped without waiting on the peerset
+/// round-trip, the same pattern `DiscoveryBehaviour` uses for its own ban list.
+pub struct CustomProto<TMessage, TSubstream> {
+	/// The protocol messages are exchanged on.
+	protocol: RegisteredProtocol<TMessage>,
+	/// Handle to the peerset, which is what actually admits or refuses connections.
+	peerset: peerset::PeersetMut,
+	/// Peers we're currently connected to, and the endpoint we're connected to them on.
+	connected: HashMap<PeerId, ConnectedPoint>,
+	/// Peers refused locally until the given instant, mirroring a ban reported to the peerset.
+	banned: HashMap<PeerId, Instant>,
+	/// Messages queued to be sent on the next `poll`.
+	pending_messages: Vec<(PeerId, TMessage)>,
+	/// Events to report to the rest of the behaviour.
+	pending_events: Vec<CustomProtoOut<TMessage>>,
+	_marker: std::marker::PhantomData<TSubstream>,
+}
+
+impl<TMessage, TSubstream> CustomProto<TMessage, TSubstream> {
+	/// Creates a new `CustomProto` running `protocol`, with peer admission driven by `peerset`.
+	pub fn new(protocol: RegisteredProtocol<TMessage>, peerset: peerset::PeersetMut) -> Self {
+		CustomProto {
+			protocol,
+			peerset,
+			connected: HashMap::new(),
+			banned: HashMap::new(),
+			pending_messages: Vec::new(),
+			pending_events: Vec::new(),
+			_marker: std::marker::PhantomData,
+		}
+	}
+
+	/// Returns true if `peer_id` is currently within its local ban cooldown.
+	fn is_banned(&self, peer_id: &PeerId) -> bool {
+		self.banned.get(peer_id).map_or(false, |until| Instant::now() < *until)
+	}
+
+	/// Returns true if we try to open the custom protocol with `peer_id`.
+	pub fn is_enabled(&self, peer_id: &PeerId) -> bool {
+		!self.is_banned(peer_id)
+	}
+
+	/// Returns true if the custom protocol is currently open with `peer_id`.
+	pub fn is_open(&self, peer_id: &PeerId) -> bool {
+		self.connected.contains_key(peer_id) && !self.is_banned(peer_id)
+	}
+
+	/// Queues `message` to be sent to `target` on the next `poll`.
+	///
+	/// Has no effect if the custom protocol is not open with `target`.
+	pub fn send_packet(&mut self, target: &PeerId, message: TMessage) {
+		if !self.is_open(target) {
+			return;
+		}
+		self.pending_messages.push((target.clone(), message));
+	}
+
+	/// Disconnects the custom protocol from `peer_id`.
+	///
+	/// The peer may reconnect immediately unless it's also been banned; see [`Self::ban_peer`].
+	pub fn disconnect_peer(&mut self, peer_id: &PeerId) {
+		if let Some(endpoint) = self.connected.remove(peer_id) {
+			self.pending_events.push(CustomProtoOut::CustomProtocolClosed {
+				peer_id: peer_id.clone(),
+				endpoint,
+				result: Ok(()),
+			});
+		}
+	}
+
+	/// Reports a reputation change for `peer_id` to the peerset. This is the only thing that
+	/// actually influences whether the peerset keeps admitting connections from `peer_id`.
+	pub fn report_peer(&mut self, peer_id: &PeerId, score_diff: i32) {
+		self.peerset.report_peer(peer_id.clone(), score_diff);
+	}
+
+	/// Refuses `peer_id` until `until`.
+	///
+	/// The peerset only deals in reputation deltas, not explicit bans, so this reports a change
+	/// large enough to push any peer below its internal disconnect threshold regardless of prior
+	/// reputation; `until` is tracked locally so we keep refusing the peer even after the
+	/// peerset's own reputation decay would otherwise let it back in, and so an already-connected
+	/// peer gets dropped immediately rather than on its next reputation-driven disconnect.
+	pub fn ban_peer(&mut self, peer_id: PeerId, until: Instant) {
+		self.peerset.report_peer(peer_id.clone(), i32::min_value());
+		self.banned.insert(peer_id.clone(), until);
+		self.disconnect_peer(&peer_id);
+	}
+
+	/// Notifies the peerset that we've discovered `peer_id`, so it can be considered as a future
+	/// connection candidate.
+	pub fn add_discovered_node(&mut self, peer_id: &PeerId) {
+		self.peerset.add_discovered_node(peer_id.clone());
+	}
+
+	/// Returns the state of the peerset manager, for debugging purposes.
+	pub fn peerset_debug_info(&self) -> serde_json::Value {
+		self.peerset.debug_info()
+	}
+}
+
+impl<TMessage, TSubstream> NetworkBehaviour for CustomProto<TMessage, TSubstream>
+where
+	TMessage: Into<Vec<u8>> + From<Vec<u8>> + Send + 'static,
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+{
+	type ProtocolsHandler = OneShotHandler<
+		TSubstream,
+		RegisteredProtocol<TMessage>,
+		OutboundMessage<TMessage>,
+		Option<TMessage>,
+	>;
+	type OutEvent = CustomProtoOut<TMessage>;
+
+	fn new_handler(&mut self) -> Self::ProtocolsHandler {
+		OneShotHandler::new(self.protocol.clone(), OneShotHandlerConfig::default())
+	}
+
+	fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+		Vec::new()
+	}
+
+	fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+		if self.is_banned(&peer_id) {
+			return;
+		}
+		self.connected.insert(peer_id.clone(), endpoint.clone());
+		self.pending_events.push(CustomProtoOut::CustomProtocolOpen { version: 1, peer_id, endpoint });
+	}
+
+	fn inject_disconnected(&mut self, peer_id: &PeerId, endpoint: ConnectedPoint) {
+		if self.connected.remove(peer_id).is_some() {
+			self.pending_events.push(CustomProtoOut::CustomProtocolClosed {
+				peer_id: peer_id.clone(),
+				endpoint,
+				result: Ok(()),
+			});
+		}
+	}
+
+	fn inject_replaced(&mut self, peer_id: PeerId, _closed: ConnectedPoint, opened: ConnectedPoint) {
+		self.connected.insert(peer_id, opened);
+	}
+
+	fn inject_node_event(&mut self, peer_id: PeerId, event: Option<TMessage>) {
+		let message = match event {
+			Some(message) => message,
+			None => return,
+		};
+		let endpoint = match self.connected.get(&peer_id) {
+			Some(endpoint) => endpoint.clone(),
+			None => return,
+		};
+		self.pending_events.push(CustomProtoOut::CustomMessage { peer_id, endpoint, message });
+	}
+
+	fn poll(
+		&mut self,
+		_params: &mut PollParameters,
+	) -> futures::Async<
+		NetworkBehaviourAction<<Self::ProtocolsHandler as ProtocolsHandler>::InEvent, Self::OutEvent>,
+	> {
+		if !self.pending_events.is_empty() {
+			return futures::Async::Ready(NetworkBehaviourAction::GenerateEvent(self.pending_events.remove(0)));
+		}
+
+		if !self.pending_messages.is_empty() {
+			let (peer_id, message) = self.pending_messages.remove(0);
+			return futures::Async::Ready(NetworkBehaviourAction::SendEvent {
+				peer_id,
+				event: OutboundMessage { protocol: self.protocol.id.clone(), message },
+			});
+		}
+
+		futures::Async::NotReady
+	}
+}
@@ -0,0 +1,222 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Demultiplexes a single connection's substreams across several independently-registered
+//! Kademlia protocols.
+//!
+//! `DiscoveryBehaviour` keeps one `Kademlia<TSubstream>` per registered `ProtocolId` so that
+//! nodes speaking several chains/protocols don't mix their DHT routing tables. Every `Kademlia`
+//! instance has the same concrete `ProtocolsHandler` type, but each is configured with its own
+//! wire protocol name. `MultiKademliaHandler` wraps one inner handler per protocol and, on the
+//! wire, advertises *all* of their protocol names at once (tagged by `ProtocolId`); whichever
+//! name multistream-select actually negotiates tells us which single inner handler the substream
+//! belongs to, so a substream is only ever handed to, and only ever updates, the one Kademlia
+//! instance whose protocol was actually spoken.
+
+use crate::custom_proto::ProtocolId;
+use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::core::protocols_handler::{
+	KeepAlive, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr, SubstreamProtocol,
+};
+use libp2p::core::swarm::NetworkBehaviour;
+use libp2p::kad::Kademlia;
+use futures::prelude::*;
+use std::collections::HashMap;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The `ProtocolsHandler` type of a `Kademlia<TSubstream>`. Identical for every registered
+/// protocol, since it only depends on `TSubstream`, not on the runtime-configured protocol name.
+type KadHandler<TSubstream> = <Kademlia<TSubstream> as NetworkBehaviour>::ProtocolsHandler;
+
+/// A protocol name, tagged with the `ProtocolId` of the Kademlia instance it was advertised for.
+#[derive(Clone)]
+pub struct TaggedProtocolName<TInfo> {
+	protocol: ProtocolId,
+	inner: TInfo,
+}
+
+impl<TInfo: AsRef<[u8]>> AsRef<[u8]> for TaggedProtocolName<TInfo> {
+	fn as_ref(&self) -> &[u8] {
+		self.inner.as_ref()
+	}
+}
+
+/// Combines several instances of the same upgrade type into one, tagging each with the
+/// `ProtocolId` it belongs to so that the side that negotiates the upgrade can tell which one
+/// was actually selected.
+pub struct MultiUpgrade<TUpgrade> {
+	items: Vec<(ProtocolId, TUpgrade)>,
+}
+
+impl<TUpgrade: UpgradeInfo> UpgradeInfo for MultiUpgrade<TUpgrade> {
+	type Info = TaggedProtocolName<TUpgrade::Info>;
+	type InfoIter = std::vec::IntoIter<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		self.items.iter()
+			.flat_map(|(protocol, upgrade)| {
+				let protocol = protocol.clone();
+				upgrade.protocol_info().into_iter()
+					.map(move |inner| TaggedProtocolName { protocol: protocol.clone(), inner })
+			})
+			.collect::<Vec<_>>()
+			.into_iter()
+	}
+}
+
+impl<TSubstream, TUpgrade> InboundUpgrade<TSubstream> for MultiUpgrade<TUpgrade>
+where
+	TUpgrade: InboundUpgrade<TSubstream>,
+{
+	type Output = (ProtocolId, TUpgrade::Output);
+	type Error = TUpgrade::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error>>;
+
+	fn upgrade_inbound(self, socket: TSubstream, info: Self::Info) -> Self::Future {
+		let TaggedProtocolName { protocol, inner } = info;
+		let matched = self.items.into_iter().find(|(p, _)| *p == protocol);
+		match matched {
+			Some((protocol, upgrade)) => {
+				let protocol = protocol.clone();
+				Box::new(upgrade.upgrade_inbound(socket, inner).map(move |out| (protocol, out)))
+			}
+			// The remote negotiated a protocol name we didn't actually advertise anymore (e.g.
+			// it was removed between `listen_protocol` and the upgrade completing). Never reached
+			// in practice since the set of registered protocols doesn't change mid-connection.
+			None => unreachable!("negotiated protocol was offered in protocol_info"),
+		}
+	}
+}
+
+impl<TSubstream, TUpgrade> OutboundUpgrade<TSubstream> for MultiUpgrade<TUpgrade>
+where
+	TUpgrade: OutboundUpgrade<TSubstream>,
+{
+	type Output = (ProtocolId, TUpgrade::Output);
+	type Error = TUpgrade::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error>>;
+
+	fn upgrade_outbound(self, socket: TSubstream, info: Self::Info) -> Self::Future {
+		let TaggedProtocolName { protocol, inner } = info;
+		let matched = self.items.into_iter().find(|(p, _)| *p == protocol);
+		match matched {
+			Some((protocol, upgrade)) => {
+				let protocol = protocol.clone();
+				Box::new(upgrade.upgrade_outbound(socket, inner).map(move |out| (protocol, out)))
+			}
+			None => unreachable!("negotiated protocol was offered in protocol_info"),
+		}
+	}
+}
+
+/// `ProtocolsHandler` that demultiplexes substreams across one inner Kademlia handler per
+/// registered protocol. Built fresh for every connection from the current set of Kademlia
+/// instances; if no protocol is registered it is simply empty and never negotiates anything,
+/// rather than panicking.
+pub struct MultiKademliaHandler<TSubstream> {
+	handlers: HashMap<ProtocolId, KadHandler<TSubstream>>,
+}
+
+impl<TSubstream> MultiKademliaHandler<TSubstream> {
+	/// Builds a new handler from the current set of registered Kademlia instances.
+	pub fn new(kademlias: &mut HashMap<ProtocolId, Kademlia<TSubstream>>) -> Self {
+		let handlers = kademlias.iter_mut()
+			.map(|(protocol, kademlia)| (protocol.clone(), NetworkBehaviour::new_handler(kademlia)))
+			.collect();
+		MultiKademliaHandler { handlers }
+	}
+}
+
+impl<TSubstream> ProtocolsHandler for MultiKademliaHandler<TSubstream>
+where
+	TSubstream: AsyncRead + AsyncWrite,
+{
+	type InEvent = (ProtocolId, <KadHandler<TSubstream> as ProtocolsHandler>::InEvent);
+	type OutEvent = (ProtocolId, <KadHandler<TSubstream> as ProtocolsHandler>::OutEvent);
+	type Error = <KadHandler<TSubstream> as ProtocolsHandler>::Error;
+	type Substream = TSubstream;
+	type InboundProtocol = MultiUpgrade<<KadHandler<TSubstream> as ProtocolsHandler>::InboundProtocol>;
+	type OutboundProtocol = <KadHandler<TSubstream> as ProtocolsHandler>::OutboundProtocol;
+	type OutboundOpenInfo = (ProtocolId, <KadHandler<TSubstream> as ProtocolsHandler>::OutboundOpenInfo);
+
+	fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+		let items = self.handlers.iter()
+			.map(|(protocol, handler)| (protocol.clone(), handler.listen_protocol().into_upgrade()))
+			.collect();
+		SubstreamProtocol::new(MultiUpgrade { items })
+	}
+
+	fn inject_fully_negotiated_inbound(
+		&mut self,
+		(protocol, output): <Self::InboundProtocol as InboundUpgrade<TSubstream>>::Output,
+	) {
+		if let Some(handler) = self.handlers.get_mut(&protocol) {
+			handler.inject_fully_negotiated_inbound(output);
+		}
+	}
+
+	fn inject_fully_negotiated_outbound(
+		&mut self,
+		output: <Self::OutboundProtocol as OutboundUpgrade<TSubstream>>::Output,
+		(protocol, info): Self::OutboundOpenInfo,
+	) {
+		if let Some(handler) = self.handlers.get_mut(&protocol) {
+			handler.inject_fully_negotiated_outbound(output, info);
+		}
+	}
+
+	fn inject_event(&mut self, (protocol, event): Self::InEvent) {
+		if let Some(handler) = self.handlers.get_mut(&protocol) {
+			handler.inject_event(event);
+		}
+	}
+
+	fn inject_dial_upgrade_error(
+		&mut self,
+		(protocol, info): Self::OutboundOpenInfo,
+		error: ProtocolsHandlerUpgrErr<<Self::OutboundProtocol as OutboundUpgrade<TSubstream>>::Error>,
+	) {
+		if let Some(handler) = self.handlers.get_mut(&protocol) {
+			handler.inject_dial_upgrade_error(info, error);
+		}
+	}
+
+	fn connection_keep_alive(&self) -> KeepAlive {
+		self.handlers.values()
+			.map(|handler| handler.connection_keep_alive())
+			.max()
+			.unwrap_or(KeepAlive::No)
+	}
+
+	fn poll(
+		&mut self,
+	) -> Poll<ProtocolsHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::OutEvent>, Self::Error> {
+		for (protocol, handler) in self.handlers.iter_mut() {
+			match handler.poll()? {
+				Async::Ready(ProtocolsHandlerEvent::Custom(event)) =>
+					return Ok(Async::Ready(ProtocolsHandlerEvent::Custom((protocol.clone(), event)))),
+				Async::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { protocol: upgrade, info }) =>
+					return Ok(Async::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+						protocol: upgrade,
+						info: (protocol.clone(), info),
+					})),
+				Async::NotReady => {}
+			}
+		}
+
+		Ok(Async::NotReady)
+	}
+}
@@ -0,0 +1,343 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! AutoNAT-style reachability probing.
+//!
+//! This behaves like a lightweight variant of the libp2p `autonat` protocol: every so often we
+//! pick a handful of already-connected peers, ask them to dial us back on one of our candidate
+//! external addresses, and count how many succeed. Once enough peers confirm they could reach
+//! us, we consider ourselves publicly reachable ("server" mode); if dial-backs keep failing we
+//! fall back to "client" mode, where we still query the DHT but stop advertising ourselves and
+//! stop storing records on behalf of others.
+
+use libp2p::core::{Multiaddr, PeerId, ProtocolsHandler};
+use libp2p::core::swarm::{ConnectedPoint, NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::core::protocols_handler::{OneShotHandler, OneShotHandlerConfig};
+use log::{debug, trace};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+
+use crate::nat_protocol::{NatProbeEvent, NatProbeProtocol};
+
+/// How many connected peers we ask to dial us back in a single probe round.
+const PROBE_BATCH_SIZE: usize = 3;
+
+/// How long we wait for a peer we asked to dial us back before counting the probe as failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Whether we believe we're publicly reachable, and the address peers confirmed us on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatStatus {
+	/// We haven't gathered enough dial-back attempts yet to make a determination.
+	Unknown,
+	/// A quorum of peers successfully dialed us back on the contained address.
+	Public(Multiaddr),
+	/// A quorum of dial-back attempts failed; we're probably behind a NAT.
+	Private,
+}
+
+/// Event emitted by `NatBehaviour` whenever the reachability determination is updated.
+#[derive(Debug, Clone)]
+pub struct NatStatusChanged {
+	/// The new status.
+	pub status: NatStatus,
+}
+
+/// `NetworkBehaviour` that periodically asks connected peers to dial us back, in order to
+/// determine whether we're publicly reachable or stuck behind a NAT.
+pub struct NatBehaviour<TSubstream> {
+	/// Candidate addresses that we believe might be externally reachable.
+	candidates: Vec<Multiaddr>,
+	/// Current reachability determination.
+	status: NatStatus,
+	/// Peers we're currently connected to, and thus eligible to be asked for a probe.
+	connected: HashSet<PeerId>,
+	/// Outstanding dial-back requests we sent out, and which candidate address (plus when) we
+	/// asked each peer to dial us back on.
+	pending: HashMap<PeerId, (Instant, Multiaddr)>,
+	/// Successful dial-backs since the last determination.
+	successes: usize,
+	/// Failed dial-backs since the last determination.
+	failures: usize,
+	/// Number of successes (or failures) needed to reach a quorum.
+	quorum: usize,
+	/// Timer firing when it's time to probe a new batch of peers.
+	next_probe: Delay,
+	/// Delay between two probe rounds.
+	probe_interval: Duration,
+	/// Peers queued to be sent a dial-back request on the next `poll`.
+	peers_to_probe: Vec<PeerId>,
+	/// Candidate addresses other peers asked *us* to dial them back on, queued to actually be
+	/// dialed on the next `poll`.
+	addrs_to_dial_back: Vec<Multiaddr>,
+	/// Events to report to the rest of the behaviour.
+	pending_events: Vec<NatStatusChanged>,
+	_marker: std::marker::PhantomData<TSubstream>,
+}
+
+impl<TSubstream> NatBehaviour<TSubstream> {
+	/// Creates a new `NatBehaviour`. `quorum` is how many successful (or failed) dial-backs are
+	/// required before flipping the public/private determination.
+	pub fn new(quorum: usize) -> Self {
+		NatBehaviour {
+			candidates: Vec::new(),
+			status: NatStatus::Unknown,
+			connected: HashSet::new(),
+			pending: HashMap::new(),
+			successes: 0,
+			failures: 0,
+			quorum,
+			next_probe: Delay::new(Instant::now() + Duration::from_secs(30)),
+			probe_interval: Duration::from_secs(30),
+			peers_to_probe: Vec::new(),
+			addrs_to_dial_back: Vec::new(),
+			pending_events: Vec::new(),
+			_marker: std::marker::PhantomData,
+		}
+	}
+
+	/// Registers an address we believe might be externally reachable, so that it can be
+	/// suggested to peers during a dial-back probe.
+	pub fn add_candidate_address(&mut self, addr: Multiaddr) {
+		if !self.candidates.contains(&addr) {
+			self.candidates.push(addr);
+		}
+	}
+
+	/// Returns the current reachability determination.
+	pub fn status(&self) -> &NatStatus {
+		&self.status
+	}
+
+	/// Records that `confirmed` actually dialed us back, confirming that exact address is
+	/// reachable.
+	fn record_success(&mut self, confirmed: Multiaddr) {
+		self.successes += 1;
+		if self.successes >= self.quorum {
+			self.set_status(NatStatus::Public(confirmed));
+		}
+	}
+
+	/// Records that a dial-back attempt failed or timed out.
+	fn record_failure(&mut self) {
+		self.failures += 1;
+		if self.failures >= self.quorum {
+			self.set_status(NatStatus::Private);
+		}
+	}
+
+	fn set_status(&mut self, status: NatStatus) {
+		if self.status != status {
+			debug!(target: crate::LOG_TARGET, "NAT status changed: {:?} => {:?}", self.status, status);
+			self.status = status.clone();
+			self.successes = 0;
+			self.failures = 0;
+			self.pending_events.push(NatStatusChanged { status });
+		}
+	}
+
+	/// Pops the next queued peer and sends it a dial-back request for one of our candidate
+	/// addresses, or `None` if there's nothing to probe, or no candidate address to offer it yet
+	/// (without one there'd be nothing concrete for the peer to dial, and a later success could
+	/// never be tied to any address we actually care about).
+	fn next_probe_event(&mut self) -> Option<NetworkBehaviourAction<NatProbeProtocol, NatStatusChanged>> {
+		if self.peers_to_probe.is_empty() {
+			return None;
+		}
+		let candidate = match self.candidates.first() {
+			Some(candidate) => candidate.clone(),
+			None => {
+				trace!(target: crate::LOG_TARGET, "No candidate address to probe with yet");
+				return None;
+			}
+		};
+
+		let peer_id = self.peers_to_probe.remove(0);
+		self.pending.insert(peer_id.clone(), (Instant::now(), candidate.clone()));
+		Some(NetworkBehaviourAction::SendEvent { peer_id, event: NatProbeProtocol { candidate } })
+	}
+
+	/// Checks whether `endpoint` confirms an outstanding dial-back request for `peer_id`.
+	///
+	/// Only a peer *dialing us* counts as a confirmed dial-back: if we're the one who just dialed
+	/// them, that tells us nothing about whether they could reach us. It only confirms the
+	/// specific candidate address we asked them to dial, matched against `listen_addr` (the
+	/// address this connection actually arrived on) — not just "some previously probed peer
+	/// reconnected somehow", which wouldn't tell us anything about a particular address's
+	/// reachability.
+	fn check_dial_back(&mut self, peer_id: &PeerId, endpoint: &ConnectedPoint) {
+		if let ConnectedPoint::Listener { listen_addr, .. } = endpoint {
+			if let Some((_, candidate)) = self.pending.get(peer_id) {
+				if candidate == listen_addr {
+					let candidate = candidate.clone();
+					self.pending.remove(peer_id);
+					trace!(target: crate::LOG_TARGET, "Dial-back from {:?} on {:?} succeeded",
+						peer_id, candidate);
+					self.record_success(candidate);
+				}
+			}
+		}
+	}
+}
+
+impl<TSubstream> NetworkBehaviour for NatBehaviour<TSubstream>
+where
+	TSubstream: AsyncRead + AsyncWrite,
+{
+	type ProtocolsHandler = OneShotHandler<TSubstream, NatProbeProtocol, NatProbeProtocol, NatProbeEvent>;
+	type OutEvent = NatStatusChanged;
+
+	fn new_handler(&mut self) -> Self::ProtocolsHandler {
+		// The listen protocol's own `candidate` is never read on the inbound side (see
+		// `NatProbeProtocol`'s docs), so the placeholder from `Default` is fine here.
+		OneShotHandler::new(NatProbeProtocol::default(), OneShotHandlerConfig::default())
+	}
+
+	fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+		Vec::new()
+	}
+
+	fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+		self.connected.insert(peer_id.clone());
+		self.check_dial_back(&peer_id, &endpoint);
+	}
+
+	fn inject_disconnected(&mut self, peer_id: &PeerId, _endpoint: ConnectedPoint) {
+		self.connected.remove(peer_id);
+		if self.pending.remove(peer_id).is_some() {
+			self.record_failure();
+		}
+	}
+
+	fn inject_replaced(&mut self, peer_id: PeerId, _closed: ConnectedPoint, opened: ConnectedPoint) {
+		// We probe peers pulled from `self.connected`, i.e. peers we're already connected to: a
+		// successful dial-back is therefore always a *second* connection to an already-connected
+		// peer, which this single-connection-per-peer swarm reports as a replacement, not a fresh
+		// `inject_connected`. Without this, every real dial-back would just run out the clock as
+		// an unanswered probe.
+		self.check_dial_back(&peer_id, &opened);
+	}
+
+	fn inject_node_event(&mut self, peer_id: PeerId, event: NatProbeEvent) {
+		trace!(target: crate::LOG_TARGET, "{:?} asked us to dial them back on {:?} for a NAT probe",
+			peer_id, event.candidate);
+		self.addrs_to_dial_back.push(event.candidate);
+	}
+
+	fn poll(
+		&mut self,
+		_params: &mut PollParameters,
+	) -> futures::Async<
+		NetworkBehaviourAction<
+			<Self::ProtocolsHandler as ProtocolsHandler>::InEvent,
+			Self::OutEvent,
+		>,
+	> {
+		if !self.pending_events.is_empty() {
+			return futures::Async::Ready(NetworkBehaviourAction::GenerateEvent(self.pending_events.remove(0)));
+		}
+
+		let timed_out: Vec<PeerId> = self.pending.iter()
+			.filter(|(_, (sent_at, _))| sent_at.elapsed() > PROBE_TIMEOUT)
+			.map(|(peer_id, _)| peer_id.clone())
+			.collect();
+		for peer_id in timed_out {
+			self.pending.remove(&peer_id);
+			debug!(target: crate::LOG_TARGET, "Dial-back from {:?} timed out", peer_id);
+			self.record_failure();
+		}
+		if !self.pending_events.is_empty() {
+			return futures::Async::Ready(NetworkBehaviourAction::GenerateEvent(self.pending_events.remove(0)));
+		}
+
+		if !self.addrs_to_dial_back.is_empty() {
+			let address = self.addrs_to_dial_back.remove(0);
+			return futures::Async::Ready(NetworkBehaviourAction::DialAddress { address });
+		}
+
+		if let Some(event) = self.next_probe_event() {
+			return futures::Async::Ready(event);
+		}
+
+		match self.next_probe.poll() {
+			Ok(futures::Async::Ready(_)) => {
+				self.next_probe.reset(Instant::now() + self.probe_interval);
+
+				let peers: Vec<PeerId> = self.connected.iter()
+					.filter(|peer_id| !self.pending.contains_key(*peer_id))
+					.take(PROBE_BATCH_SIZE)
+					.cloned()
+					.collect();
+				if peers.is_empty() {
+					trace!(target: crate::LOG_TARGET, "No connected peers available for a NAT probe round");
+				}
+				self.peers_to_probe.extend(peers);
+
+				if let Some(event) = self.next_probe_event() {
+					return futures::Async::Ready(event);
+				}
+			}
+			Ok(futures::Async::NotReady) => {}
+			Err(err) => debug!(target: crate::LOG_TARGET, "NAT probe timer errored: {:?}", err),
+		}
+
+		futures::Async::NotReady
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn dial_back_confirmed_via_already_connected_peer() {
+		// Every peer we probe is, by construction, already connected to us (see `poll`'s
+		// `self.connected` filter), so a real dial-back always arrives as a second connection to
+		// an already-connected peer, which this single-connection-per-peer swarm reports through
+		// `inject_replaced`, not `inject_connected`.
+		let candidate: Multiaddr = "/ip4/1.2.3.4/tcp/30333".parse().unwrap();
+		let mut nat = NatBehaviour::<()>::new(1);
+		let peer_id = PeerId::random();
+		nat.pending.insert(peer_id.clone(), (Instant::now(), candidate.clone()));
+
+		nat.check_dial_back(&peer_id, &ConnectedPoint::Listener {
+			listen_addr: candidate.clone(),
+			send_back_addr: candidate.clone(),
+		});
+
+		assert_eq!(*nat.status(), NatStatus::Public(candidate));
+		assert!(!nat.pending.contains_key(&peer_id));
+	}
+
+	#[test]
+	fn dial_back_on_a_different_address_is_not_confirmed() {
+		let candidate: Multiaddr = "/ip4/1.2.3.4/tcp/30333".parse().unwrap();
+		let other: Multiaddr = "/ip4/5.6.7.8/tcp/30333".parse().unwrap();
+		let mut nat = NatBehaviour::<()>::new(1);
+		let peer_id = PeerId::random();
+		nat.pending.insert(peer_id.clone(), (Instant::now(), candidate));
+
+		nat.check_dial_back(&peer_id, &ConnectedPoint::Listener {
+			listen_addr: other.clone(),
+			send_back_addr: other,
+		});
+
+		assert_eq!(*nat.status(), NatStatus::Unknown);
+		assert!(nat.pending.contains_key(&peer_id));
+	}
+}
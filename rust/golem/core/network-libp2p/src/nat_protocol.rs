@@ -0,0 +1,139 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Wire protocol used by [`crate::nat::NatBehaviour`] to ask a peer to dial us back.
+//!
+//! Unlike a bare "please dial me back" ping, the substream carries the specific candidate
+//! address we want dialed: the prober writes it and disconnects, so whether the probe succeeded
+//! is entirely `NatBehaviour`'s call, matched against that exact address rather than against
+//! "some previously-probed peer reconnected somehow" — see `NatBehaviour::check_dial_back`. On the listening side the payload isn't echoed back; negotiating the
+//! substream and reading the address off it is all this protocol does, the actual dial-back
+//! itself is driven by `NatBehaviour` queuing a `DialAddress` action.
+
+use futures::prelude::*;
+use futures::future;
+use libp2p::core::{InboundUpgrade, Multiaddr, OutboundUpgrade, UpgradeInfo};
+use std::{convert::TryFrom, io, iter};
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Name of the substream protocol used for NAT dial-back probes.
+const NAT_PROBE_PROTOCOL: &[u8] = b"/substrate/nat-probe/1.0.0";
+
+/// Upper bound on an encoded probe address, so a peer can't make us allocate an unbounded buffer
+/// by sending a bogus length prefix.
+const MAX_MESSAGE_SIZE: u32 = 4 * 1024;
+
+/// Upgrade negotiated to ask a peer to dial us back on a specific candidate address.
+///
+/// `candidate` only matters for the outbound (probing) side, which writes it to the substream;
+/// the inbound (probed) side ignores its own field and just reads whatever address the prober
+/// sent.
+#[derive(Debug, Clone)]
+pub struct NatProbeProtocol {
+	/// The candidate address we're asking the remote to dial us back on.
+	pub candidate: Multiaddr,
+}
+
+impl Default for NatProbeProtocol {
+	fn default() -> Self {
+		NatProbeProtocol { candidate: Multiaddr::empty() }
+	}
+}
+
+/// Reported once a [`NatProbeProtocol`] substream is negotiated: the remote is asking to be
+/// dialed back on `candidate`.
+#[derive(Debug, Clone)]
+pub struct NatProbeEvent {
+	/// The address the remote wants us to dial it back on.
+	pub candidate: Multiaddr,
+}
+
+impl UpgradeInfo for NatProbeProtocol {
+	type Info = &'static [u8];
+	type InfoIter = iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		iter::once(NAT_PROBE_PROTOCOL)
+	}
+}
+
+/// Reverses the encoding written by `upgrade_outbound`.
+fn decode(buf: &[u8]) -> Result<Multiaddr, io::Error> {
+	Multiaddr::try_from(buf.to_vec())
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed NAT probe address"))
+}
+
+impl<TSubstream> InboundUpgrade<TSubstream> for NatProbeProtocol
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+{
+	type Output = NatProbeEvent;
+	type Error = io::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error> + Send>;
+
+	fn upgrade_inbound(self, socket: TSubstream, _info: Self::Info) -> Self::Future {
+		let probe_future = read_exact(socket, [0u8; 4])
+			.and_then(|(socket, len_buf)| {
+				let len = u32::from_le_bytes(len_buf);
+				if len > MAX_MESSAGE_SIZE {
+					let err = io::Error::new(io::ErrorKind::InvalidData, "NAT probe message exceeds max size");
+					return future::Either::A(future::err::<(TSubstream, Vec<u8>), io::Error>(err));
+				}
+				future::Either::B(read_exact(socket, vec![0u8; len as usize]))
+			})
+			.and_then(|(_socket, buf)| decode(&buf))
+			.map(|candidate| NatProbeEvent { candidate });
+		Box::new(probe_future)
+	}
+}
+
+impl<TSubstream> OutboundUpgrade<TSubstream> for NatProbeProtocol
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+{
+	type Output = NatProbeEvent;
+	type Error = io::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error> + Send>;
+
+	fn upgrade_outbound(self, socket: TSubstream, _info: Self::Info) -> Self::Future {
+		let payload = self.candidate.to_vec();
+		let mut framed = Vec::with_capacity(4 + payload.len());
+		framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+		framed.extend_from_slice(&payload);
+
+		let candidate = self.candidate;
+		let future = write_all(socket, framed).map(move |_| NatProbeEvent { candidate });
+		Box::new(future)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn decode_rejects_malformed_address() {
+		assert!(decode(&[1, 2, 3]).is_err());
+	}
+
+	#[test]
+	fn decode_accepts_encoded_address() {
+		let addr: Multiaddr = "/ip4/1.2.3.4/tcp/30333".parse().unwrap();
+		let info = decode(&addr.to_vec()).unwrap();
+		assert_eq!(info, addr);
+	}
+}
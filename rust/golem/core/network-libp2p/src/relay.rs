@@ -0,0 +1,331 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Circuit-relay + direct-connection-upgrade-through-relay (DCUtR) hole punching.
+//!
+//! Two nodes that are both behind NATs can't dial each other directly. `RelayBehaviour` offers
+//! circuit-relay addresses (`<relay>/p2p-circuit/p2p/<target>`) for any peer through every relay
+//! it knows about, so the swarm can reach the target through a relay peer when it has no direct
+//! address for it. Once connected that way, both ends immediately negotiate
+//! [`crate::relay_protocol::HolePunchProtocol`] over the relayed connection to exchange their own
+//! candidate external addresses and a tie-breaking nonce, then each dials the other's observed
+//! address directly so that the NAT mapping each side's outbound dial creates lines up with the
+//! other side's inbound dial. See [`crate::relay_protocol`] for the nonce tie-break; the resolved
+//! role is only logged and does not change how the direct connection is negotiated.
+
+use libp2p::core::{ConnectedPoint, Multiaddr, PeerId, ProtocolsHandler};
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::core::protocols_handler::{OneShotHandler, OneShotHandlerConfig};
+use log::{debug, trace};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+
+use crate::relay_protocol::{resolve_role, HolePunchInfo, HolePunchProtocol, HolePunchRole};
+
+/// How long we wait for a dial-back to land before declaring a hole punch attempt failed.
+const HOLE_PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Event emitted by [`RelayBehaviour`].
+#[derive(Debug, Clone)]
+pub enum RelayEvent {
+	/// We established a direct connection to `peer_id` after punching through NATs.
+	HolePunched {
+		/// Id of the peer we punched through to.
+		peer_id: PeerId,
+		/// The direct connection's endpoint.
+		endpoint: ConnectedPoint,
+	},
+	/// A hole punch attempt with `peer_id` didn't result in a direct connection in time.
+	HolePunchFailed {
+		/// Id of the peer the attempt was with.
+		peer_id: PeerId,
+	},
+}
+
+/// State of an in-progress hole punch attempt.
+struct PunchAttempt {
+	/// Nonce we sent to the remote for the simultaneous-open tie-break.
+	our_nonce: u64,
+	/// When the attempt was started, so we can time it out.
+	started_at: Instant,
+}
+
+/// Returns true if `addr` is a circuit-relay address (i.e. routes through a relay peer rather
+/// than reaching its target directly).
+fn is_circuit_address(addr: &Multiaddr) -> bool {
+	addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// `NetworkBehaviour` implementing circuit relay dialing and DCUtR hole punching.
+pub struct RelayBehaviour<TSubstream> {
+	/// Peers we can use as a relay, and their address.
+	relays: Vec<(PeerId, Multiaddr)>,
+	/// Our own candidate external addresses, offered to peers we hole-punch with.
+	candidate_addrs: Vec<Multiaddr>,
+	/// Hole punch attempts currently in flight, keyed by the peer we're punching through to.
+	attempts: HashMap<PeerId, PunchAttempt>,
+	/// Our own nonce for the simultaneous-open tie-break, fixed for the lifetime of this
+	/// behaviour rather than tracked per connection. A per-connection nonce can only be recorded
+	/// for the side that proactively dials through the relay (see `pending_exchange`); the far
+	/// end of that same connection sees it as an inbound connection and never runs that
+	/// bookkeeping, yet still needs *a* nonce to resolve the tie-break when the address exchange
+	/// lands on it. Using one fixed nonce means both sides can always resolve the role without
+	/// needing to have recorded anything about the specific connection first.
+	our_nonce: u64,
+	/// Peers we just connected to through a relay, queued to start an address exchange with on
+	/// the next `poll`.
+	pending_exchange: Vec<PeerId>,
+	/// Addresses queued to be dialed directly on the next `poll`, as the active half of a hole
+	/// punch.
+	pending_dials: Vec<Multiaddr>,
+	/// Events to report to the rest of the behaviour.
+	pending_events: Vec<RelayEvent>,
+	/// Timer used to time out stale attempts.
+	sweep_timer: Delay,
+	_marker: std::marker::PhantomData<TSubstream>,
+}
+
+impl<TSubstream> RelayBehaviour<TSubstream> {
+	/// Creates a new, empty `RelayBehaviour`.
+	pub fn new() -> Self {
+		RelayBehaviour {
+			relays: Vec::new(),
+			candidate_addrs: Vec::new(),
+			attempts: HashMap::new(),
+			our_nonce: rand::random(),
+			pending_exchange: Vec::new(),
+			pending_dials: Vec::new(),
+			pending_events: Vec::new(),
+			sweep_timer: Delay::new(Instant::now() + Duration::from_secs(1)),
+			_marker: std::marker::PhantomData,
+		}
+	}
+
+	/// Registers `peer_id` as a relay we can dial `target`-behind-NAT peers through.
+	pub fn add_relay(&mut self, peer_id: PeerId, addr: Multiaddr) {
+		if self.relays.iter().all(|(p, a)| *p != peer_id || *a != addr) {
+			self.relays.push((peer_id, addr));
+		}
+	}
+
+	/// Registers an address we believe might be externally reachable, offered to peers during a
+	/// hole punch address exchange.
+	pub fn add_candidate_address(&mut self, addr: Multiaddr) {
+		if !self.candidate_addrs.contains(&addr) {
+			self.candidate_addrs.push(addr);
+		}
+	}
+
+	/// Starts a hole punch attempt towards `peer_id`, who we've just learned is reachable at
+	/// `observed_addr` through a relayed address exchange. Both sides are expected to dial each
+	/// other's observed address at roughly the same time; see [`crate::relay_protocol`].
+	pub fn start_hole_punch(&mut self, peer_id: PeerId, _observed_addr: Multiaddr, our_nonce: u64) {
+		self.attempts.insert(peer_id, PunchAttempt { our_nonce, started_at: Instant::now() });
+	}
+
+	/// Checks whether `endpoint` resolves an outstanding hole punch attempt for `peer_id`, and
+	/// reports [`RelayEvent::HolePunched`] if so. Returns whether it did.
+	fn check_hole_punch_success(&mut self, peer_id: &PeerId, endpoint: &ConnectedPoint) -> bool {
+		if self.attempts.remove(peer_id).is_some() {
+			trace!(target: crate::LOG_TARGET, "Hole punch to {:?} succeeded via {:?}", peer_id, endpoint);
+			self.pending_events.push(RelayEvent::HolePunched { peer_id: peer_id.clone(), endpoint: endpoint.clone() });
+			true
+		} else {
+			false
+		}
+	}
+}
+
+impl<TSubstream> NetworkBehaviour for RelayBehaviour<TSubstream>
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+{
+	type ProtocolsHandler = OneShotHandler<TSubstream, HolePunchProtocol, HolePunchProtocol, HolePunchInfo>;
+	type OutEvent = RelayEvent;
+
+	fn new_handler(&mut self) -> Self::ProtocolsHandler {
+		OneShotHandler::new(
+			HolePunchProtocol { our_nonce: self.our_nonce, our_addrs: self.candidate_addrs.clone() },
+			OneShotHandlerConfig::default(),
+		)
+	}
+
+	fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+		let direct: Vec<Multiaddr> = self.relays.iter()
+			.filter_map(|(p, a)| if p == peer_id { Some(a.clone()) } else { None })
+			.collect();
+		if !direct.is_empty() {
+			// `peer_id` is itself one of our relays; dial it directly.
+			return direct;
+		}
+
+		// Otherwise, offer a circuit-relay address through every relay we know, so the swarm can
+		// reach `peer_id` even though we have no direct address for it.
+		self.relays.iter()
+			.map(|(relay_peer, relay_addr)| {
+				let mut addr = relay_addr.clone();
+				addr.push(Protocol::P2p(relay_peer.clone().into()));
+				addr.push(Protocol::P2pCircuit);
+				addr.push(Protocol::P2p(peer_id.clone().into()));
+				addr
+			})
+			.collect()
+	}
+
+	fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+		if self.check_hole_punch_success(&peer_id, &endpoint) {
+			return;
+		}
+
+		if let ConnectedPoint::Dialer { address } = &endpoint {
+			if is_circuit_address(address) {
+				trace!(target: crate::LOG_TARGET, "Connected to {:?} via relay, starting address \
+					exchange for a hole punch", peer_id);
+				self.pending_exchange.push(peer_id);
+			}
+		}
+	}
+
+	fn inject_disconnected(&mut self, _peer_id: &PeerId, _endpoint: ConnectedPoint) {}
+
+	fn inject_replaced(&mut self, peer_id: PeerId, _closed: ConnectedPoint, opened: ConnectedPoint) {
+		// We dial the very peer we're already talking to over the relay circuit, so a successful
+		// hole punch is always a *second* connection to an already-connected peer, which this
+		// single-connection-per-peer swarm reports as a replacement, not a fresh `inject_connected`.
+		// Without this, every real hole punch would just run out the clock as an unanswered attempt.
+		self.check_hole_punch_success(&peer_id, &opened);
+	}
+
+	fn inject_node_event(&mut self, peer_id: PeerId, info: HolePunchInfo) {
+		// Reached by both sides of the relayed connection: the dialer, once its outbound address
+		// exchange completes, and the NAT'd target, once it receives the dialer's inbound
+		// exchange. Using `self.our_nonce` (fixed for this behaviour's lifetime) instead of a
+		// per-connection nonce means both sides can resolve the tie-break here, so both actually
+		// queue a direct dial, as DCUtR requires.
+		//
+		// The resolved role is logged only, not wired into anything further: `NetworkBehaviour`
+		// has no hook to influence who leads multistream-select on the resulting connection, and
+		// we go through the same `NetworkBehaviourAction::DialAddress` on both sides regardless of
+		// role. Driving actual negotiation order off `resolve_role` needs support from the
+		// transport/muxer layer below this behaviour, which isn't plumbed through yet.
+		match resolve_role(self.our_nonce, info.remote_nonce) {
+			HolePunchRole::Initiator =>
+				trace!(target: crate::LOG_TARGET, "Won simultaneous-open tie-break with {:?}, \
+					taking initiator role", peer_id),
+			HolePunchRole::Responder =>
+				trace!(target: crate::LOG_TARGET, "Lost simultaneous-open tie-break with {:?}, \
+					taking responder role", peer_id),
+		}
+
+		if let Some(target_addr) = info.remote_addrs.into_iter().next() {
+			self.start_hole_punch(peer_id, target_addr.clone(), self.our_nonce);
+			self.pending_dials.push(target_addr);
+		} else {
+			debug!(target: crate::LOG_TARGET, "{:?} didn't offer any address to hole punch to", peer_id);
+		}
+	}
+
+	fn poll(
+		&mut self,
+		_params: &mut PollParameters,
+	) -> futures::Async<
+		NetworkBehaviourAction<
+			<Self::ProtocolsHandler as ProtocolsHandler>::InEvent,
+			Self::OutEvent,
+		>,
+	> {
+		if !self.pending_events.is_empty() {
+			return futures::Async::Ready(NetworkBehaviourAction::GenerateEvent(self.pending_events.remove(0)));
+		}
+
+		if !self.pending_dials.is_empty() {
+			let address = self.pending_dials.remove(0);
+			return futures::Async::Ready(NetworkBehaviourAction::DialAddress { address });
+		}
+
+		if !self.pending_exchange.is_empty() {
+			let peer_id = self.pending_exchange.remove(0);
+			return futures::Async::Ready(NetworkBehaviourAction::SendEvent {
+				peer_id,
+				event: HolePunchProtocol { our_nonce: self.our_nonce, our_addrs: self.candidate_addrs.clone() },
+			});
+		}
+
+		if let Ok(futures::Async::Ready(_)) = self.sweep_timer.poll() {
+			self.sweep_timer.reset(Instant::now() + Duration::from_secs(1));
+
+			let timed_out: Vec<PeerId> = self.attempts.iter()
+				.filter(|(_, attempt)| attempt.started_at.elapsed() > HOLE_PUNCH_TIMEOUT)
+				.map(|(peer_id, _)| peer_id.clone())
+				.collect();
+			for peer_id in timed_out {
+				self.attempts.remove(&peer_id);
+				debug!(target: crate::LOG_TARGET, "Hole punch to {:?} timed out", peer_id);
+				self.pending_events.push(RelayEvent::HolePunchFailed { peer_id });
+			}
+
+			if !self.pending_events.is_empty() {
+				return futures::Async::Ready(NetworkBehaviourAction::GenerateEvent(self.pending_events.remove(0)));
+			}
+		}
+
+		futures::Async::NotReady
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn hole_punch_confirmed_via_already_connected_peer() {
+		// We dial the very peer we're already talking to over the relay circuit, so a successful
+		// hole punch always arrives as a second connection to an already-connected peer, which
+		// this single-connection-per-peer swarm reports through `inject_replaced`, not
+		// `inject_connected`.
+		let mut relay = RelayBehaviour::<()>::new();
+		let peer_id = PeerId::random();
+		relay.attempts.insert(peer_id.clone(), PunchAttempt { our_nonce: 42, started_at: Instant::now() });
+
+		let direct: Multiaddr = "/ip4/1.2.3.4/tcp/30333".parse().unwrap();
+		let confirmed = relay.check_hole_punch_success(
+			&peer_id,
+			&ConnectedPoint::Dialer { address: direct },
+		);
+
+		assert!(confirmed);
+		assert!(!relay.attempts.contains_key(&peer_id));
+		assert!(matches!(relay.pending_events.first(), Some(RelayEvent::HolePunched { .. })));
+	}
+
+	#[test]
+	fn unrelated_connection_does_not_confirm_a_hole_punch() {
+		let mut relay = RelayBehaviour::<()>::new();
+		let peer_id = PeerId::random();
+
+		let direct: Multiaddr = "/ip4/1.2.3.4/tcp/30333".parse().unwrap();
+		let confirmed = relay.check_hole_punch_success(
+			&peer_id,
+			&ConnectedPoint::Dialer { address: direct },
+		);
+
+		assert!(!confirmed);
+		assert!(relay.pending_events.is_empty());
+	}
+}
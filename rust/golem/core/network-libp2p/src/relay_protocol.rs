@@ -0,0 +1,240 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Wire protocol used by [`crate::relay::RelayBehaviour`] over a relayed connection to agree on
+//! a simultaneous-open hole punch.
+//!
+//! Two peers that are both behind NATs first connect to each other through a relay. Over that
+//! relayed connection, both ends immediately negotiate this protocol and write their own nonce
+//! and their own candidate external addresses to the substream, then read the remote's. Once both
+//! sides have the other's observed address they each dial it directly at roughly the same time,
+//! so that the NAT mapping each side's outbound dial creates lines up with the other side's
+//! inbound dial.
+//!
+//! Because both sides are dialing simultaneously, neither is unambiguously the "initiator" for the
+//! protocol negotiation that follows on the resulting direct connection. [`resolve_role`]
+//! computes a role from the exchanged nonces, with the higher value becoming the initiator; the
+//! negotiation on the direct connection does not use that role.
+
+use libp2p::core::{InboundUpgrade, Multiaddr, OutboundUpgrade, UpgradeInfo};
+use futures::prelude::*;
+use futures::future;
+use std::{convert::TryFrom, io, iter, mem};
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Name of the substream protocol used to exchange addresses and synchronize a simultaneous-open
+/// hole punch.
+const HOLE_PUNCH_PROTOCOL: &[u8] = b"/substrate/dcutr/1.0.0";
+
+/// Upper bound on an encoded exchange message, so a peer can't make us allocate an unbounded
+/// buffer by sending a bogus length prefix.
+const MAX_MESSAGE_SIZE: u32 = 16 * 1024;
+
+/// Upper bound on the number of addresses a single exchange message can carry, checked before
+/// `Vec::with_capacity` so a bogus count can't trigger a multi-gigabyte allocation attempt off of
+/// a few bytes of input.
+const MAX_ADDRESS_COUNT: u32 = 64;
+
+/// Upgrade negotiated on both ends of a relayed connection to exchange candidate addresses and
+/// pick which side is the initiator for the direct connection's protocol negotiation.
+#[derive(Debug, Clone)]
+pub struct HolePunchProtocol {
+	/// Nonce we send to the remote. The side with the higher nonce becomes the initiator.
+	pub our_nonce: u64,
+	/// Our candidate external addresses, offered to the remote to dial us on directly.
+	pub our_addrs: Vec<Multiaddr>,
+}
+
+/// What we learned from the remote side of a [`HolePunchProtocol`] exchange.
+#[derive(Debug, Clone)]
+pub struct HolePunchInfo {
+	/// The remote's nonce.
+	pub remote_nonce: u64,
+	/// The remote's candidate external addresses.
+	pub remote_addrs: Vec<Multiaddr>,
+}
+
+/// Result of negotiating a [`HolePunchProtocol`] substream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolePunchRole {
+	/// Our nonce won the tie-break; we drive the subsequent protocol negotiation.
+	Initiator,
+	/// The remote's nonce won; we wait for them to negotiate.
+	Responder,
+}
+
+impl UpgradeInfo for HolePunchProtocol {
+	type Info = &'static [u8];
+	type InfoIter = iter::Once<Self::Info>;
+
+	fn protocol_info(&self) -> Self::InfoIter {
+		iter::once(HOLE_PUNCH_PROTOCOL)
+	}
+}
+
+/// Decides, from two exchanged nonces, which side is the initiator.
+///
+/// Ties (vanishingly unlikely with a `u64`) resolve to the responder role on both sides, so with
+/// equal nonces neither peer takes the initiator role.
+///
+/// Note: the caller ([`crate::relay::RelayBehaviour::inject_node_event`]) currently only logs the
+/// resolved role; it isn't yet wired into which side leads multistream-select on the punched
+/// connection, since that decision happens below the `NetworkBehaviour` layer this code lives at.
+pub fn resolve_role(our_nonce: u64, remote_nonce: u64) -> HolePunchRole {
+	if our_nonce > remote_nonce {
+		HolePunchRole::Initiator
+	} else {
+		HolePunchRole::Responder
+	}
+}
+
+/// Encodes a nonce and a list of addresses as `[nonce: 8 bytes LE][count: 4 bytes LE][(len: 4
+/// bytes LE, addr bytes) ...]`.
+fn encode(nonce: u64, addrs: &[Multiaddr]) -> Vec<u8> {
+	let mut out = Vec::new();
+	out.extend_from_slice(&nonce.to_le_bytes());
+	out.extend_from_slice(&(addrs.len() as u32).to_le_bytes());
+	for addr in addrs {
+		let bytes = addr.to_vec();
+		out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+		out.extend_from_slice(&bytes);
+	}
+	out
+}
+
+/// Reverses [`encode`].
+fn decode(buf: &[u8]) -> Result<HolePunchInfo, io::Error> {
+	let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed hole punch message");
+
+	if buf.len() < mem::size_of::<u64>() + mem::size_of::<u32>() {
+		return Err(invalid());
+	}
+	let (nonce_bytes, rest) = buf.split_at(mem::size_of::<u64>());
+	let remote_nonce = u64::from_le_bytes(<[u8; 8]>::try_from(nonce_bytes).map_err(|_| invalid())?);
+
+	let (count_bytes, mut rest) = rest.split_at(mem::size_of::<u32>());
+	let count = u32::from_le_bytes(<[u8; 4]>::try_from(count_bytes).map_err(|_| invalid())?);
+	if count > MAX_ADDRESS_COUNT {
+		return Err(invalid());
+	}
+
+	let mut remote_addrs = Vec::with_capacity(count as usize);
+	for _ in 0..count {
+		if rest.len() < mem::size_of::<u32>() {
+			return Err(invalid());
+		}
+		let (len_bytes, after_len) = rest.split_at(mem::size_of::<u32>());
+		let len = u32::from_le_bytes(<[u8; 4]>::try_from(len_bytes).map_err(|_| invalid())?) as usize;
+		if after_len.len() < len {
+			return Err(invalid());
+		}
+		let (addr_bytes, after_addr) = after_len.split_at(len);
+		remote_addrs.push(Multiaddr::try_from(addr_bytes.to_vec()).map_err(|_| invalid())?);
+		rest = after_addr;
+	}
+
+	Ok(HolePunchInfo { remote_nonce, remote_addrs })
+}
+
+/// Writes our half of the exchange (length-prefixed) then reads the remote's, on whichever side
+/// of the substream we ended up on; the exchange doesn't depend on being the dialer or listener.
+fn exchange<TSubstream>(
+	socket: TSubstream,
+	our_nonce: u64,
+	our_addrs: Vec<Multiaddr>,
+) -> Box<dyn Future<Item = HolePunchInfo, Error = io::Error> + Send>
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+{
+	let payload = encode(our_nonce, &our_addrs);
+	let mut framed = Vec::with_capacity(4 + payload.len());
+	framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+	framed.extend_from_slice(&payload);
+
+	let future = write_all(socket, framed)
+		.and_then(|(socket, _)| read_exact(socket, [0u8; 4]))
+		.and_then(|(socket, len_buf)| {
+			let len = u32::from_le_bytes(len_buf);
+			if len > MAX_MESSAGE_SIZE {
+				let err = io::Error::new(io::ErrorKind::InvalidData, "hole punch message exceeds max size");
+				return future::Either::A(future::err::<(TSubstream, Vec<u8>), io::Error>(err));
+			}
+			future::Either::B(read_exact(socket, vec![0u8; len as usize]))
+		})
+		.and_then(|(_socket, buf)| decode(&buf));
+
+	Box::new(future)
+}
+
+impl<TSubstream> InboundUpgrade<TSubstream> for HolePunchProtocol
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+{
+	type Output = HolePunchInfo;
+	type Error = io::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error> + Send>;
+
+	fn upgrade_inbound(self, socket: TSubstream, _info: Self::Info) -> Self::Future {
+		exchange(socket, self.our_nonce, self.our_addrs)
+	}
+}
+
+impl<TSubstream> OutboundUpgrade<TSubstream> for HolePunchProtocol
+where
+	TSubstream: AsyncRead + AsyncWrite + Send + 'static,
+{
+	type Output = HolePunchInfo;
+	type Error = io::Error;
+	type Future = Box<dyn Future<Item = Self::Output, Error = Self::Error> + Send>;
+
+	fn upgrade_outbound(self, socket: TSubstream, _info: Self::Info) -> Self::Future {
+		exchange(socket, self.our_nonce, self.our_addrs)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn higher_nonce_is_initiator() {
+		assert_eq!(resolve_role(5, 3), HolePunchRole::Initiator);
+		assert_eq!(resolve_role(3, 5), HolePunchRole::Responder);
+	}
+
+	#[test]
+	fn tied_nonce_goes_to_responder() {
+		assert_eq!(resolve_role(7, 7), HolePunchRole::Responder);
+	}
+
+	#[test]
+	fn encode_decode_roundtrip() {
+		let addrs: Vec<Multiaddr> = vec![
+			"/ip4/127.0.0.1/tcp/30333".parse().unwrap(),
+			"/ip4/1.2.3.4/tcp/4001".parse().unwrap(),
+		];
+		let encoded = encode(42, &addrs);
+		let info = decode(&encoded).unwrap();
+		assert_eq!(info.remote_nonce, 42);
+		assert_eq!(info.remote_addrs, addrs);
+	}
+
+	#[test]
+	fn decode_rejects_truncated_input() {
+		assert!(decode(&[1, 2, 3]).is_err());
+	}
+}